@@ -0,0 +1,197 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, punctuated::Punctuated, Attribute, Expr, ExprLit, FnArg, GenericArgument,
+    ItemFn, Lit, LitStr, Meta, Pat, PathArguments, ReturnType, Token, Type,
+};
+
+/// Turn an async fn into a tool the model can call.
+///
+/// The doc comment of the fn is the tool description and the doc comments of the parameters
+/// describe them in the json schema. For `fn get_weather(..)` this generates `GetWeatherArgs`,
+/// the parameters as a struct, and `GetWeatherTool`, which implements `q_bot::ToolFunction`
+/// and can be added to a `q_bot::ToolRegistry`.
+///
+/// `#[tool(name = "..", description = "..")]` overrides the name and the description.
+#[proc_macro_attribute]
+pub fn tool(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr with Punctuated::<Meta, Token![,]>::parse_terminated);
+    let func = parse_macro_input!(item as ItemFn);
+    match expand(args, func) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(
+    args: Punctuated<Meta, Token![,]>,
+    mut func: ItemFn,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let mut name = None;
+    let mut description = None;
+    for arg in args {
+        let Meta::NameValue(nv) = &arg else {
+            return Err(syn::Error::new_spanned(arg, "expected `name = \"..\"`"));
+        };
+        let value = match &nv.value {
+            Expr::Lit(ExprLit {
+                lit: Lit::Str(s), ..
+            }) => s.value(),
+            _ => return Err(syn::Error::new_spanned(&nv.value, "expected a string")),
+        };
+        if nv.path.is_ident("name") {
+            name = Some(value);
+        } else if nv.path.is_ident("description") {
+            description = Some(value);
+        } else {
+            return Err(syn::Error::new_spanned(&nv.path, "unknown tool attribute"));
+        }
+    }
+
+    let fn_name = func.sig.ident.clone();
+    let name = name.unwrap_or_else(|| fn_name.to_string());
+    let description = match description.or_else(|| doc(&func.attrs)) {
+        Some(description) => description,
+        None => {
+            return Err(syn::Error::new_spanned(
+                &func.sig.ident,
+                "a tool needs a doc comment or a description",
+            ))
+        }
+    };
+
+    let mut fields = vec![];
+    let mut field_names = vec![];
+    for input in func.sig.inputs.iter_mut() {
+        let FnArg::Typed(arg) = input else {
+            return Err(syn::Error::new_spanned(input, "a tool can't take self"));
+        };
+        let Pat::Ident(ident) = arg.pat.as_ref() else {
+            return Err(syn::Error::new_spanned(
+                &arg.pat,
+                "expected a plain parameter name",
+            ));
+        };
+        let ident = ident.ident.clone();
+        let ty = &arg.ty;
+        let docs: Vec<_> = arg
+            .attrs
+            .iter()
+            .filter(|a| a.path().is_ident("doc"))
+            .cloned()
+            .collect();
+        // doc comments are not allowed on parameters, keep them for the schema only
+        arg.attrs.retain(|a| !a.path().is_ident("doc"));
+        let vis = &func.vis;
+        fields.push(quote! { #(#docs)* #vis #ident: #ty });
+        field_names.push(ident);
+    }
+
+    let (output, call) = match &func.sig.output {
+        ReturnType::Default => (quote! { () }, quote! { ::std::result::Result::Ok(ret) }),
+        ReturnType::Type(_, ty) => match result_ok_type(ty) {
+            Some(ok) => (
+                quote! { #ok },
+                quote! { ret.map_err(|e| ::std::string::ToString::to_string(&e)) },
+            ),
+            None => (quote! { #ty }, quote! { ::std::result::Result::Ok(ret) }),
+        },
+    };
+    let awaited = if func.sig.asyncness.is_some() {
+        quote! { #fn_name(#(args.#field_names),*).await }
+    } else {
+        quote! { #fn_name(#(args.#field_names),*) }
+    };
+
+    let pascal = pascal_case(&fn_name.to_string());
+    let args_ident = format_ident!("{}Args", pascal);
+    let tool_ident = format_ident!("{}Tool", pascal);
+    let vis = &func.vis;
+    let serde_path = LitStr::new("::q_bot::__private::serde", Span::call_site());
+    let schemars_path = LitStr::new("::q_bot::__private::schemars", Span::call_site());
+    let args_doc = format!("Arguments of the `{}` tool.", name);
+    let tool_doc = format!("The `{}` tool, generated by `#[tool]`.", name);
+
+    Ok(quote! {
+        #func
+
+        #[doc = #args_doc]
+        #[derive(Debug, ::q_bot::__private::serde::Deserialize, ::q_bot::__private::schemars::JsonSchema)]
+        #[serde(crate = #serde_path)]
+        #[schemars(crate = #schemars_path)]
+        #vis struct #args_ident {
+            #(#fields),*
+        }
+
+        #[doc = #tool_doc]
+        #[derive(Debug, Clone, Copy, Default)]
+        #vis struct #tool_ident;
+
+        impl ::q_bot::ToolFunction for #tool_ident {
+            type Args = #args_ident;
+            type Output = #output;
+            const NAME: &'static str = #name;
+            const DESCRIPTION: &'static str = #description;
+
+            fn call(
+                &self,
+                args: Self::Args,
+            ) -> ::q_bot::__private::BoxFuture<'static, ::std::result::Result<Self::Output, ::std::string::String>> {
+                ::std::boxed::Box::pin(async move {
+                    let ret = #awaited;
+                    #call
+                })
+            }
+        }
+    })
+}
+
+/// Join the doc comments, without the space rustdoc puts after `///`.
+fn doc(attrs: &[Attribute]) -> Option<String> {
+    let lines: Vec<String> = attrs
+        .iter()
+        .filter(|a| a.path().is_ident("doc"))
+        .filter_map(|a| match &a.meta {
+            Meta::NameValue(nv) => match &nv.value {
+                Expr::Lit(ExprLit {
+                    lit: Lit::Str(s), ..
+                }) => Some(s.value()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .map(|line| line.strip_prefix(' ').unwrap_or(&line).to_string())
+        .collect();
+    let doc = lines.join("\n").trim().to_string();
+    (!doc.is_empty()).then_some(doc)
+}
+
+/// The `T` of `Result<T, E>`, also for aliases like `anyhow::Result<T>`.
+fn result_ok_type(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Result" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    match args.args.first()? {
+        GenericArgument::Type(ty) => Some(ty),
+        _ => None,
+    }
+}
+
+fn pascal_case(name: &str) -> String {
+    name.split('_')
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let mut chars = s.chars();
+            let first = chars.next().unwrap().to_ascii_uppercase();
+            std::iter::once(first).chain(chars).collect::<String>()
+        })
+        .collect()
+}
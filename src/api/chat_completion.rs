@@ -1,21 +1,36 @@
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures::{Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, io, ops::AddAssign, path::Path};
 
-use crate::{IntoRequest, ToSchema};
+use crate::{model::model_id, Endpoint, IntoRequest, ToSchema};
 use derive_builder::Builder;
 
 #[derive(Debug, Clone, Serialize, Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
 pub struct ChatCompletionRequest {
     /// A list of messages comprising the conversation so far.w
     #[builder(setter(into))]
     messages: Vec<ChatCompletionMessage>,
     /// ID of the model to use. See the model endpoint compatibility table for details on which models work with the Chat API.
-    #[builder(default)]
+    #[builder(default, setter(into))]
     model: ChatCompleteModel,
     /// Number between -2.0 and 2.0. Positive values penalize new tokens based on their existing frequency in the text so far, decreasing the model's likelihood to repeat the same line verbatim.
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    frequency_penalty: Option<i32>,
-    // logit_bias: Option<i32>,
+    frequency_penalty: Option<f32>,
+    /// Modify the likelihood of specified tokens appearing in the completion. Maps token ids to a bias value from -100 to 100.
+    #[builder(default, setter(strip_option, into))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logit_bias: Option<BTreeMap<u32, i32>>,
+    /// Whether to return log probabilities of the output tokens or not. If true, returns the log probabilities of each output token returned in the content of message.
+    #[builder(default, setter(strip_option))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<bool>,
+    /// An integer between 0 and 20 specifying the number of most likely tokens to return at each token position. logprobs must be set to true if this parameter is used.
+    #[builder(default, setter(strip_option))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_logprobs: Option<u8>,
     /// The maximum number of tokens to generate in the chat completion.
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -27,7 +42,7 @@ pub struct ChatCompletionRequest {
     /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they appear in the text so far, increasing the model's likelihood to talk about new topics.
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    presence_penalty: Option<usize>,
+    presence_penalty: Option<f32>,
     /// An object specifying the format that the model must output.
     /// Setting to { "type": "json_object" } enables JSON mode, which guarantees the message the model generates is valid JSON.
     #[builder(default, setter(strip_option))]
@@ -38,10 +53,9 @@ pub struct ChatCompletionRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     seed: Option<usize>,
     /// Up to 4 sequences where the API will stop generating further tokens.
-    // TODO: make this as an enum
-    #[builder(default, setter(strip_option))]
+    #[builder(default, setter(strip_option, into))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    stop: Option<String>,
+    stop: Option<StopSequence>,
     /// If set, partial message deltas will be sent, like in ChatGPT. Tokens will be sent as data-only server-sent events as they become available, with the stream terminated by a data: [DONE]
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -50,12 +64,12 @@ pub struct ChatCompletionRequest {
     /// We generally recommend altering this or top_p but not both.
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    temperature: Option<i32>,
+    temperature: Option<f32>,
     /// An alternative to sampling with temperature, called nucleus sampling, where the model considers the results of the tokens with top_p probability mass. So 0.1 means only the tokens comprising the top 10% probability mass are considered.
     /// We generally recommend altering this or temperature but not both.
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    top_p: Option<i32>,
+    top_p: Option<f32>,
     /// A list of Tools the model may call. Currently, only functions are supported as a tool. Use this to provide a list of functions the model may generate JSON inputs for.
     #[builder(default, setter(into))]
     #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -70,6 +84,13 @@ pub struct ChatCompletionRequest {
     user: Option<String>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum StopSequence {
+    One(String),
+    Many(Vec<String>),
+}
+
 #[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ToolChoice {
@@ -104,6 +125,9 @@ pub struct FunctionInfo {
 #[derive(Debug, Clone, Serialize)]
 pub struct ChatResponseFormatObject {
     r#type: ChatResponseFormat,
+    /// The schema the reply must match, when the type is json_schema.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json_schema: Option<JsonSchemaFormat>,
 }
 
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
@@ -112,10 +136,23 @@ pub struct ChatResponseFormatObject {
 pub enum ChatResponseFormat {
     Text,
     #[default]
+    #[serde(rename = "json_object")]
     Json,
+    JsonSchema,
 }
 
 #[derive(Debug, Clone, Serialize)]
+pub struct JsonSchemaFormat {
+    /// The name of the response format. Must be a-z, A-Z, 0-9, or contain underscores and dashes, with a maximum length of 64.
+    name: String,
+    /// The schema for the response format, described as a JSON Schema object.
+    schema: serde_json::Value,
+    /// Whether to enable strict schema adherence. Strict mode only supports a subset of JSON Schema.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strict: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "role")]
 /// tag 指定生成字段名称
 pub enum ChatCompletionMessage {
@@ -131,23 +168,22 @@ pub enum ChatCompletionMessage {
     // Function(FunctionMessage),
 }
 
-#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub enum ChatCompleteModel {
-    #[default]
-    #[serde(rename = "gpt-3.5-turbo-1106")]
-    GPT3Turbo,
-    #[serde(rename = "gpt-3.5-turbo-instruct")]
-    GPT3TurboInstruct,
-    #[serde(rename = "gpt-4-1106-preview")]
-    GPT4Turbo,
-    #[serde(rename = "gpt-4-1106-vision-preview")]
-    GPT4TurboVersion,
-    #[serde(rename = "babbage-002")]
-    Test,
+model_id! {
+    /// ID of a chat model. Any id is accepted, the OpenAI models are available as constants.
+    ChatCompleteModel, default = GPT3_TURBO {
+        GPT3_TURBO = "gpt-3.5-turbo-1106",
+        GPT3_TURBO_INSTRUCT = "gpt-3.5-turbo-instruct",
+        GPT4_TURBO = "gpt-4-1106-preview",
+        GPT4_TURBO_VISION = "gpt-4-1106-vision-preview",
+        GPT4O = "gpt-4o",
+        GPT4O_MINI = "gpt-4o-mini",
+        O1 = "o1",
+        O3_MINI = "o3-mini",
+        BABBAGE_002 = "babbage-002",
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Builder)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
 pub struct SystemMessage {
     /// The contents of the system message
     content: String,
@@ -156,20 +192,58 @@ pub struct SystemMessage {
     name: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Builder)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
 pub struct UserMessage {
-    /// The contents of the user message
-    content: String,
+    /// The contents of the user message, text or an array of text and image parts.
+    #[builder(setter(into))]
+    content: UserContent,
     /// An optional name for the participant. Provides the model information to differentiate between participants of the same role.
     #[serde(skip_serializing_if = "Option::is_none")]
     name: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum UserContent {
+    /// The text contents of the message.
+    Text(String),
+    /// An array of content parts. Image input is only supported by vision models.
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageUrl {
+    /// Either a URL of the image or the base64 encoded image data.
+    url: String,
+    /// Specifies the detail level of the image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<ImageDetail>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageDetail {
+    #[default]
+    Auto,
+    Low,
+    High,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct AssistantMessage {
     /// The contents of the assistant message
     #[serde(skip_serializing_if = "Option::is_none")]
     content: Option<String>,
+    /// The refusal message generated by the model.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    refusal: Option<String>,
     /// An optional name for the participant. Provides the model information to differentiate between participants of the same role.
     #[serde(skip_serializing_if = "Option::is_none")]
     name: Option<String>,
@@ -178,7 +252,7 @@ pub struct AssistantMessage {
     tool_calls: Vec<ToolCalls>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ToolMessage {
     /// The contents of the tool message.
     content: String,
@@ -186,7 +260,7 @@ pub struct ToolMessage {
     tool_call_id: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct ToolCalls {
     /// The ID of the tool call.
     id: String,
@@ -196,7 +270,7 @@ pub struct ToolCalls {
     function: FunctionCall,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 struct FunctionCall {
     /// The name of the function to call.
     name: String,
@@ -211,27 +285,29 @@ pub enum ToolType {
     Function,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct ChatCompletionResponse {
     /// A unique identifier for the chat completion.
-    id: String,
+    pub id: String,
     /// A list of chat completionchoices. Can be more than one if n is greater than 1.
     #[serde(skip_serializing_if = "Vec::is_empty")]
-    choices: Vec<ChatCompletionChoice>,
+    pub choices: Vec<ChatCompletionChoice>,
     /// The Unix timestamp (in seconds) of when the chat completion was created.
     pub created: usize,
     /// The model used for the chat completion.
     pub model: ChatCompleteModel,
     /// This fingerprint represents the backend configuration that the model runs with.
     /// Can be used in conjunction with the seed request parameter to understand when backend changes have been made that might impact determinism.
-    pub system_fingerprint: String,
+    /// Some OpenAI compatible servers don't send it.
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
     /// The object type, which is always chat.completion.
     pub object: String,
     /// Usage statistics for the completion request.
     pub usage: ChatCompletionUsage,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct ChatCompletionChoice {
     /// The reason the model stopped generating tokens. This will be stop if the model hit a natural stop point or a provided stop sequence, length if the maximum number of tokens specified in the request was reached, content_filter if content was omitted due to a flag from our content filters, tool_calls if the model called a tool, or function_call (deprecated) if the model called a function.
     pub finish_reason: FinishReason,
@@ -239,16 +315,51 @@ pub struct ChatCompletionChoice {
     pub index: usize,
     /// A chat completion message generated by the model.
     pub message: AssistantMessage,
+    /// Log probability information for the choice, if logprobs was requested.
+    #[serde(default)]
+    pub logprobs: Option<ChatCompletionLogprobs>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct ChatCompletionLogprobs {
+    /// A list of message content tokens with log probability information.
+    #[serde(default)]
+    pub content: Option<Vec<TokenLogprob>>,
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TokenLogprob {
+    /// The token.
+    pub token: String,
+    /// The log probability of this token, if it is within the top 20 most likely tokens. Otherwise, the value -9999.0 is used to signify that the token is very unlikely.
+    pub logprob: f64,
+    /// The UTF-8 bytes of the token. Useful when characters are represented by multiple tokens and their byte representations must be combined.
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
+    /// List of the most likely tokens and their log probability, at this token position.
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TopLogprob {
+    /// The token.
+    pub token: String,
+    /// The log probability of this token.
+    pub logprob: f64,
+    /// The UTF-8 bytes of the token.
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
 pub struct ChatCompletionUsage {
     /// Number of tokens in the generated completion.
-    completion_tokens: usize,
+    pub completion_tokens: usize,
     /// Number of tokens in the prompt.
-    prompt_tokens: usize,
+    pub prompt_tokens: usize,
     /// Total number of tokens used in the request (prompt + completion).
-    total_tokens: usize,
+    pub total_tokens: usize,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
@@ -261,9 +372,315 @@ pub enum FinishReason {
     ToolCalls,
 }
 
+/// A streamed chunk of a chat completion response, returned by `LLmSdk::chat_completion_stream`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatCompletionChunk {
+    /// A unique identifier for the chat completion. Each chunk has the same ID.
+    pub id: String,
+    /// A list of chat completion choices. Can be more than one if n is greater than 1.
+    #[serde(default)]
+    pub choices: Vec<ChatCompletionChunkChoice>,
+    /// The Unix timestamp (in seconds) of when the chat completion was created. Each chunk has the same timestamp.
+    pub created: usize,
+    /// The model to generate the completion.
+    pub model: ChatCompleteModel,
+    /// This fingerprint represents the backend configuration that the model runs with.
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
+    /// The object type, which is always chat.completion.chunk.
+    pub object: String,
+    /// Usage statistics, only present in the last chunk when the server is asked to include it.
+    #[serde(default)]
+    pub usage: Option<ChatCompletionUsage>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatCompletionChunkChoice {
+    /// A chat completion delta generated by streamed model responses.
+    pub delta: ChatCompletionDelta,
+    /// The reason the model stopped generating tokens, only present in the last chunk of the choice.
+    #[serde(default)]
+    pub finish_reason: Option<FinishReason>,
+    /// The index of the choice in the list of choices.
+    pub index: usize,
+    /// Log probability information of the tokens in this chunk, if logprobs was requested.
+    #[serde(default)]
+    pub logprobs: Option<ChatCompletionLogprobs>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ChatCompletionDelta {
+    /// The role of the author of this message, only present in the first chunk.
+    #[serde(default)]
+    pub role: Option<String>,
+    /// The contents of the chunk message.
+    #[serde(default)]
+    pub content: Option<String>,
+    /// Fragments of the tool calls generated by the model.
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCallDelta>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ToolCallDelta {
+    /// The index of the tool call in the message, used to join the fragments.
+    pub index: usize,
+    /// The ID of the tool call, only present in the first fragment.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// The type of the tool. Currently, only function is supported.
+    #[serde(default)]
+    pub r#type: Option<ToolType>,
+    /// The function name and a fragment of its arguments.
+    #[serde(default)]
+    pub function: Option<FunctionCallDelta>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FunctionCallDelta {
+    /// The name of the function to call, only present in the first fragment.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// A fragment of the arguments to call the function with.
+    #[serde(default)]
+    pub arguments: Option<String>,
+}
+
+impl ChatCompletionResponse {
+    /// Fold streamed chunks back into a full response.
+    pub fn from_chunks(chunks: impl IntoIterator<Item = ChatCompletionChunk>) -> Self {
+        let mut res = ChatCompletionResponse {
+            object: "chat.completion".to_string(),
+            ..Default::default()
+        };
+        for chunk in chunks {
+            res.push_chunk(chunk);
+        }
+        res
+    }
+
+    /// Consume a chunk stream and fold it into a full response.
+    pub async fn from_stream(
+        stream: impl Stream<Item = crate::Result<ChatCompletionChunk>>,
+    ) -> crate::Result<Self> {
+        let chunks: Vec<_> = stream.try_collect().await?;
+        Ok(Self::from_chunks(chunks))
+    }
+
+    fn push_chunk(&mut self, chunk: ChatCompletionChunk) {
+        self.id = chunk.id;
+        self.created = chunk.created;
+        self.model = chunk.model;
+        if chunk.system_fingerprint.is_some() {
+            self.system_fingerprint = chunk.system_fingerprint;
+        }
+        if let Some(usage) = chunk.usage {
+            self.usage = usage;
+        }
+
+        for delta in chunk.choices {
+            let choice = match self.choices.iter().position(|c| c.index == delta.index) {
+                Some(pos) => &mut self.choices[pos],
+                None => {
+                    self.choices.push(ChatCompletionChoice {
+                        index: delta.index,
+                        ..Default::default()
+                    });
+                    self.choices.last_mut().unwrap()
+                }
+            };
+            if let Some(reason) = delta.finish_reason {
+                choice.finish_reason = reason;
+            }
+            if let Some(tokens) = delta.logprobs.and_then(|l| l.content) {
+                let logprobs = choice.logprobs.get_or_insert_with(Default::default);
+                logprobs.content.get_or_insert_with(Vec::new).extend(tokens);
+            }
+            choice.message.push_delta(delta.delta);
+        }
+        self.choices.sort_by_key(|c| c.index);
+    }
+}
+
+impl AssistantMessage {
+    fn push_delta(&mut self, delta: ChatCompletionDelta) {
+        if let Some(content) = delta.content {
+            self.content
+                .get_or_insert_with(String::new)
+                .push_str(&content);
+        }
+        for call in delta.tool_calls {
+            if self.tool_calls.len() <= call.index {
+                self.tool_calls.resize(call.index + 1, ToolCalls::default());
+            }
+            let tool_call = &mut self.tool_calls[call.index];
+            if let Some(id) = call.id {
+                tool_call.id = id;
+            }
+            if let Some(r#type) = call.r#type {
+                tool_call.r#type = r#type;
+            }
+            if let Some(function) = call.function {
+                if let Some(name) = function.name {
+                    tool_call.function.name.push_str(&name);
+                }
+                if let Some(arguments) = function.arguments {
+                    tool_call.function.arguments.push_str(&arguments);
+                }
+            }
+        }
+    }
+}
+
+impl ChatCompletionRequest {
+    /// The messages of the conversation so far.
+    pub fn messages(&self) -> &[ChatCompletionMessage] {
+        &self.messages
+    }
+
+    /// The tools the model may call.
+    pub fn tools(&self) -> &[Tool] {
+        &self.tools
+    }
+
+    pub fn push_message(&mut self, message: ChatCompletionMessage) {
+        self.messages.push(message);
+    }
+
+    /// Add the tools whose name isn't offered by the request yet.
+    pub fn add_tools(&mut self, tools: impl IntoIterator<Item = Tool>) {
+        for tool in tools {
+            if !self.tools.iter().any(|t| t.name() == tool.name()) {
+                self.tools.push(tool);
+            }
+        }
+    }
+
+    pub fn model(&self) -> &ChatCompleteModel {
+        &self.model
+    }
+
+    /// The maximum number of tokens to generate, if limited.
+    pub fn max_tokens(&self) -> Option<usize> {
+        self.max_tokens
+    }
+
+    /// The messages, for `Conversation` and the context strategies to edit the history in place.
+    pub(crate) fn messages_mut(&mut self) -> &mut Vec<ChatCompletionMessage> {
+        &mut self.messages
+    }
+
+    pub(crate) fn with_stream(mut self, stream: bool) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+
+    pub(crate) fn with_response_format(mut self, format: ChatResponseFormatObject) -> Self {
+        self.response_format = Some(format);
+        self
+    }
+}
+
+impl AddAssign for ChatCompletionUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.completion_tokens += other.completion_tokens;
+        self.prompt_tokens += other.prompt_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+impl ChatResponseFormatObject {
+    pub fn text() -> Self {
+        Self::new(ChatResponseFormat::Text)
+    }
+
+    /// JSON mode, the model replies with valid JSON.
+    pub fn json() -> Self {
+        Self::new(ChatResponseFormat::Json)
+    }
+
+    /// Structured outputs, the model replies with JSON matching the schema of `T`.
+    pub fn json_schema<T: ToSchema>() -> Self {
+        let schema = T::to_schema();
+        let title = schema["title"].as_str().unwrap_or("response");
+        let mut name: String = title
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+            .collect();
+        name.truncate(64);
+        ChatResponseFormatObject {
+            r#type: ChatResponseFormat::JsonSchema,
+            json_schema: Some(JsonSchemaFormat {
+                name,
+                schema,
+                strict: None,
+            }),
+        }
+    }
+
+    /// Enable strict schema adherence. The schema must then stick to the subset strict mode supports.
+    pub fn strict(mut self, strict: bool) -> Self {
+        if let Some(format) = self.json_schema.as_mut() {
+            format.strict = Some(strict);
+        }
+        self
+    }
+
+    fn new(r#type: ChatResponseFormat) -> Self {
+        ChatResponseFormatObject {
+            r#type,
+            json_schema: None,
+        }
+    }
+}
+
+impl SystemMessage {
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+impl AssistantMessage {
+    pub fn content(&self) -> Option<&str> {
+        self.content.as_deref()
+    }
+
+    pub fn refusal(&self) -> Option<&str> {
+        self.refusal.as_deref()
+    }
+
+    pub fn tool_calls(&self) -> &[ToolCalls] {
+        &self.tool_calls
+    }
+}
+
+impl ToolCalls {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The name of the function to call.
+    pub fn name(&self) -> &str {
+        &self.function.name
+    }
+
+    /// The arguments to call the function with, as generated by the model in JSON format.
+    pub fn arguments(&self) -> &str {
+        &self.function.arguments
+    }
+}
+
 impl IntoRequest for ChatCompletionRequest {
-    fn into_request(self, client: reqwest::Client) -> reqwest::RequestBuilder {
-        let url = format!("{}{}", crate::SDK.base_url, "/chat/completions");
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::ChatCompletions
+    }
+
+    fn into_request(self, base_url: &str, client: reqwest::Client) -> reqwest::RequestBuilder {
+        let url = format!("{}{}", base_url, "/chat/completions");
         client.post(url).json(&self)
     }
 }
@@ -277,19 +694,158 @@ impl ChatCompletionMessage {
         })
     }
 
-    pub fn new_user(content: impl Into<String>, name: &str) -> ChatCompletionMessage {
+    /// A user message, from text or from content parts built with `ContentPart`.
+    pub fn new_user(content: impl Into<UserContent>, name: &str) -> ChatCompletionMessage {
         ChatCompletionMessage::User(UserMessage {
             content: content.into(),
             name: Self::get_name(name),
         })
     }
 
+    /// An assistant reply, e.g. for few-shot examples.
+    pub fn new_assistant(content: impl Into<String>) -> ChatCompletionMessage {
+        ChatCompletionMessage::Assistant(AssistantMessage {
+            content: Some(content.into()),
+            refusal: None,
+            name: None,
+            tool_calls: vec![],
+        })
+    }
+
+    pub fn new_tool(content: impl Into<String>, tool_call_id: impl Into<String>) -> Self {
+        ChatCompletionMessage::Tool(ToolMessage {
+            content: content.into(),
+            tool_call_id: tool_call_id.into(),
+        })
+    }
+
     #[inline]
     fn get_name(name: &str) -> Option<String> {
         (!name.is_empty()).then(|| name.into())
     }
 }
 
+impl ChatCompletionRequestBuilder {
+    fn validate(&self) -> Result<(), String> {
+        fn check(name: &str, value: Option<Option<f32>>, min: f32, max: f32) -> Result<(), String> {
+            match value.flatten() {
+                Some(v) if !(min..=max).contains(&v) => Err(format!(
+                    "{} must be between {} and {}, got {}",
+                    name, min, max, v
+                )),
+                _ => Ok(()),
+            }
+        }
+        check("temperature", self.temperature, 0.0, 2.0)?;
+        check("top_p", self.top_p, 0.0, 1.0)?;
+        check("frequency_penalty", self.frequency_penalty, -2.0, 2.0)?;
+        check("presence_penalty", self.presence_penalty, -2.0, 2.0)?;
+
+        if let Some(Some(bias)) = &self.logit_bias {
+            if let Some((token, v)) = bias.iter().find(|(_, v)| !(-100..=100).contains(*v)) {
+                return Err(format!(
+                    "logit_bias of token {} must be between -100 and 100, got {}",
+                    token, v
+                ));
+            }
+        }
+        if let Some(Some(StopSequence::Many(stop))) = &self.stop {
+            if stop.len() > 4 {
+                return Err(format!("up to 4 stop sequences, got {}", stop.len()));
+            }
+        }
+        if let Some(Some(top)) = self.top_logprobs {
+            if top > 20 {
+                return Err(format!("top_logprobs must be at most 20, got {}", top));
+            }
+            if self.logprobs.flatten() != Some(true) {
+                return Err("top_logprobs requires logprobs to be true".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<String> for StopSequence {
+    fn from(value: String) -> Self {
+        StopSequence::One(value)
+    }
+}
+
+impl From<&str> for StopSequence {
+    fn from(value: &str) -> Self {
+        StopSequence::One(value.into())
+    }
+}
+
+impl From<Vec<String>> for StopSequence {
+    fn from(value: Vec<String>) -> Self {
+        StopSequence::Many(value)
+    }
+}
+
+impl From<Vec<&str>> for StopSequence {
+    fn from(value: Vec<&str>) -> Self {
+        StopSequence::Many(value.into_iter().map(String::from).collect())
+    }
+}
+
+impl From<String> for UserContent {
+    fn from(value: String) -> Self {
+        UserContent::Text(value)
+    }
+}
+
+impl From<&str> for UserContent {
+    fn from(value: &str) -> Self {
+        UserContent::Text(value.into())
+    }
+}
+
+impl From<Vec<ContentPart>> for UserContent {
+    fn from(value: Vec<ContentPart>) -> Self {
+        UserContent::Parts(value)
+    }
+}
+
+impl ContentPart {
+    pub fn text(text: impl Into<String>) -> Self {
+        ContentPart::Text { text: text.into() }
+    }
+
+    /// An image the API downloads from `url`.
+    pub fn image_url(url: impl Into<String>, detail: Option<ImageDetail>) -> Self {
+        ContentPart::ImageUrl {
+            image_url: ImageUrl {
+                url: url.into(),
+                detail,
+            },
+        }
+    }
+
+    /// An image sent inline as a base64 data url. `mime` is e.g. `image/png`.
+    pub fn image_base64(data: &[u8], mime: &str, detail: Option<ImageDetail>) -> Self {
+        let url = format!("data:{};base64,{}", mime, BASE64_STANDARD.encode(data));
+        Self::image_url(url, detail)
+    }
+
+    /// Load a local PNG or JPEG file as an inline image.
+    pub fn image_file(path: impl AsRef<Path>, detail: Option<ImageDetail>) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        let mime = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            "image/png"
+        } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+            "image/jpeg"
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "only PNG and JPEG images are supported",
+            ));
+        };
+        Ok(Self::image_base64(&data, mime, detail))
+    }
+}
+
 impl Tool {
     #[allow(dead_code)]
     pub fn new_function<T: ToSchema>(name: impl Into<String>, des: impl Into<String>) -> Tool {
@@ -302,11 +858,18 @@ impl Tool {
             },
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.function.name
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::ToSchema;
+    use crate::{
+        testing::{MockResponse, MockServer},
+        Endpoint, ToSchema,
+    };
 
     use super::*;
     use anyhow::{Ok, Result};
@@ -411,26 +974,201 @@ mod tests {
         );
     }
 
+    #[test]
+    fn chat_response_format_serialize_should_work() {
+        let req = get_simple_completion_request().with_response_format(
+            ChatResponseFormatObject::json_schema::<GetWeatherArgs>().strict(false),
+        );
+        let json = serde_json::to_value(req).unwrap();
+        assert_eq!(
+            json["response_format"],
+            serde_json::json!({
+                "type": "json_schema",
+                "json_schema": {
+                    "name": "GetWeatherArgs",
+                    "schema": GetWeatherArgs::to_schema(),
+                    "strict": false
+                }
+            })
+        );
+
+        assert_eq!(
+            serde_json::to_value(ChatResponseFormatObject::json()).unwrap(),
+            serde_json::json!({ "type": "json_object" })
+        );
+    }
+
+    #[tokio::test]
+    async fn chat_completion_typed_should_work() -> Result<()> {
+        let messages = vec![
+            ChatCompletionMessage::new_system("Extract the weather query of the user.", ""),
+            ChatCompletionMessage::new_user("How hot is it in ShangHai, in celsius?", ""),
+        ];
+        let req = ChatCompletionRequestBuilder::default()
+            .messages(messages)
+            .build()?;
+        let server = MockServer::start().await;
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::chat(r#"{"city": "ShangHai"}"#),
+        );
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::chat(r#"{"city": "ShangHai", "unit": "Celsius"}"#),
+        );
+        let args: GetWeatherArgs = server.sdk().chat_completion_typed(req, 1).await?;
+
+        assert_eq!(args.city, "ShangHai");
+        assert_eq!(args.unit, TemperatureUnit::Celsius);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        let body = requests[1].json().unwrap();
+        assert_eq!(body["response_format"]["type"], "json_schema");
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[2]["content"], r#"{"city": "ShangHai"}"#);
+        assert!(messages[3]["content"]
+            .as_str()
+            .unwrap()
+            .starts_with("Your reply is invalid"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn multimodal_user_message_serialize_should_work() -> Result<()> {
+        let path = std::env::temp_dir().join("q-bot-pixel.png");
+        fs::write(&path, b"\x89PNG\r\n\x1a\n")?;
+        let message = ChatCompletionMessage::new_user(
+            vec![
+                ContentPart::text("What's in these images?"),
+                ContentPart::image_url("https://example.com/cat.jpg", None),
+                ContentPart::image_file(&path, Some(ImageDetail::Low))?,
+            ],
+            "zheng",
+        );
+
+        assert_eq!(
+            serde_json::to_value(message)?,
+            serde_json::json!({
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": "What's in these images?" },
+                    { "type": "image_url", "image_url": { "url": "https://example.com/cat.jpg" } },
+                    {
+                        "type": "image_url",
+                        "image_url": { "url": "data:image/png;base64,iVBORw0KGgo=", "detail": "low" }
+                    }
+                ],
+                "name": "zheng"
+            })
+        );
+
+        fs::write(&path, b"GIF89a")?;
+        assert!(ContentPart::image_file(&path, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn chat_completion_request_sampling_serialize_should_work() -> Result<()> {
+        let req = ChatCompletionRequestBuilder::default()
+            .messages(vec![ChatCompletionMessage::new_user("HI!", "")])
+            .temperature(0.7)
+            .top_p(0.5)
+            .frequency_penalty(-0.5)
+            .presence_penalty(1.5)
+            .logit_bias(BTreeMap::from([(50256, -100)]))
+            .stop(vec!["\n", "END"])
+            .logprobs(true)
+            .top_logprobs(2)
+            .build()?;
+        let json = serde_json::to_value(req)?;
+
+        assert_eq!(json["temperature"], 0.7_f32);
+        assert_eq!(json["top_p"], 0.5);
+        assert_eq!(json["frequency_penalty"], -0.5);
+        assert_eq!(json["presence_penalty"], 1.5);
+        assert_eq!(json["logit_bias"], serde_json::json!({ "50256": -100 }));
+        assert_eq!(json["stop"], serde_json::json!(["\n", "END"]));
+        assert_eq!(json["logprobs"], true);
+        assert_eq!(json["top_logprobs"], 2);
+
+        let req = ChatCompletionRequestBuilder::default()
+            .messages(vec![])
+            .stop("END")
+            .build()?;
+        assert_eq!(serde_json::to_value(req)?["stop"], "END");
+        Ok(())
+    }
+
+    #[test]
+    fn chat_completion_request_builder_should_validate_ranges() {
+        let mut builder = ChatCompletionRequestBuilder::default();
+        builder.messages(vec![]);
+        assert!(builder.clone().temperature(2.5).build().is_err());
+        assert!(builder.clone().top_p(-0.1).build().is_err());
+        assert!(builder.clone().presence_penalty(-2.5).build().is_err());
+        assert!(builder
+            .clone()
+            .logit_bias(BTreeMap::from([(1, 101)]))
+            .build()
+            .is_err());
+        assert!(builder
+            .clone()
+            .stop(vec!["a", "b", "c", "d", "e"])
+            .build()
+            .is_err());
+        assert!(builder.clone().top_logprobs(2).build().is_err());
+        assert!(builder
+            .clone()
+            .logprobs(true)
+            .top_logprobs(21)
+            .build()
+            .is_err());
+        assert!(builder.clone().temperature(0.0).top_p(1.0).build().is_ok());
+    }
+
+    #[test]
+    fn chat_completion_choice_logprobs_deserialize_should_work() -> Result<()> {
+        let choice: ChatCompletionChoice = serde_json::from_value(serde_json::json!({
+            "index": 0,
+            "finish_reason": "stop",
+            "message": { "role": "assistant", "content": "Hi" },
+            "logprobs": {
+                "content": [{
+                    "token": "Hi",
+                    "logprob": -0.31,
+                    "bytes": [72, 105],
+                    "top_logprobs": [
+                        { "token": "Hi", "logprob": -0.31, "bytes": [72, 105] },
+                        { "token": "Hello", "logprob": -1.4, "bytes": null }
+                    ]
+                }]
+            }
+        }))?;
+
+        let tokens = choice.logprobs.unwrap().content.unwrap();
+        assert_eq!(tokens[0].token, "Hi");
+        assert_eq!(tokens[0].bytes, Some(vec![72, 105]));
+        assert_eq!(tokens[0].top_logprobs[1].token, "Hello");
+        assert_eq!(tokens[0].top_logprobs[1].logprob, -1.4);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn simple_chat_completion_should_work() -> Result<()> {
+        let server = MockServer::start().await;
         let req = get_simple_completion_request();
-        let sdk = &crate::SDK;
-        let res = sdk.chat_completion(req).await?;
-
-        let ress = res.clone();
-        tokio::task::spawn_blocking(move || {
-            let str = format!("{:#?}", ress.clone());
-            let _ = fs::write("res.text", str);
-        })
-        .await
-        .expect("Failed to spawn blocking task");
+        let res = server.sdk().chat_completion(req).await?;
 
-        assert_eq!(res.model, ChatCompleteModel::GPT3Turbo);
+        assert_eq!(res.model, ChatCompleteModel::GPT3_TURBO);
         assert_eq!(res.choices.len(), 1);
         let choice = &res.choices[0];
         assert_eq!(choice.finish_reason, FinishReason::Stop);
         assert_eq!(choice.index, 0);
         assert_eq!(choice.message.tool_calls.len(), 0);
+        assert_eq!(choice.message.content(), Some("Mock reply to: HI!"));
 
         Ok(())
     }
@@ -480,19 +1218,17 @@ mod tests {
 
     #[tokio::test]
     async fn chat_completion_with_tools_should_work() -> Result<()> {
+        let server = MockServer::start().await;
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::tool_call(
+                "get_weather_forecast",
+                serde_json::json!({ "city": "ShangHai", "unit": "Celsius" }),
+            ),
+        );
         let req = get_tool_completion_request();
-        let sdk = &crate::SDK;
-        let res = sdk.chat_completion(req).await?;
-
-        let ress = res.clone();
-        tokio::task::spawn_blocking(move || {
-            let str = format!("{:#?}", ress.clone());
-            fs::write("res.txt", str);
-        })
-        .await
-        .expect("Failed to spawn blocking task");
+        let res = server.sdk().chat_completion(req).await?;
 
-        assert_eq!(res.model, ChatCompleteModel::GPT3Turbo);
         assert_eq!(res.choices.len(), 1);
         let choice = &res.choices[0];
         assert_eq!(choice.finish_reason, FinishReason::ToolCalls);
@@ -504,6 +1240,152 @@ mod tests {
         let ret = get_weather_forecast(serde_json::from_str(&tool_call.function.arguments)?);
         assert_eq!(ret.unit, TemperatureUnit::Celsius);
         assert_eq!(ret.temperature, 22.1);
+        let req = server.requests()[0].json().unwrap();
+        assert_eq!(req["tools"].as_array().unwrap().len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn chat_completion_model_should_accept_any_id() -> Result<()> {
+        let req = ChatCompletionRequestBuilder::default()
+            .messages(vec![])
+            .model("llama3:8b")
+            .build()?;
+        assert_eq!(serde_json::to_value(req)?["model"], "llama3:8b");
+
+        let req = ChatCompletionRequestBuilder::default()
+            .messages(vec![])
+            .model(ChatCompleteModel::GPT4O)
+            .build()?;
+        assert_eq!(serde_json::to_value(req)?["model"], "gpt-4o");
+
+        let res: ChatCompletionResponse = serde_json::from_value(serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1,
+            "model": "gpt-4o-2024-08-06",
+            "system_fingerprint": "fp_1",
+            "choices": [],
+            "usage": { "completion_tokens": 1, "prompt_tokens": 1, "total_tokens": 2 }
+        }))?;
+        assert_eq!(res.model.as_str(), "gpt-4o-2024-08-06");
+        assert_ne!(res.model, ChatCompleteModel::GPT4O);
+        Ok(())
+    }
+
+    #[test]
+    fn chat_completion_response_without_fingerprint_should_deserialize() -> Result<()> {
+        let res: ChatCompletionResponse = serde_json::from_value(serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1,
+            "model": "llama3:8b",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "Hi" },
+                "finish_reason": "stop"
+            }],
+            "usage": { "completion_tokens": 1, "prompt_tokens": 1, "total_tokens": 2 }
+        }))?;
+        assert_eq!(res.system_fingerprint, None);
+        assert_eq!(res.choices[0].message.content(), Some("Hi"));
+        Ok(())
+    }
+
+    #[test]
+    fn chat_completion_chunks_should_fold_into_response() -> Result<()> {
+        let chunks = [
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1,"model":"gpt-3.5-turbo-1106","system_fingerprint":"fp_1","choices":[{"index":0,"delta":{"role":"assistant","content":null,"tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"get_weather_forecast","arguments":""}}]},"finish_reason":null}]}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1,"model":"gpt-3.5-turbo-1106","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"city\":"}}]},"finish_reason":null}]}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1,"model":"gpt-3.5-turbo-1106","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"ShangHai\"}"}}]},"finish_reason":null}]}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1,"model":"gpt-3.5-turbo-1106","choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}]}"#,
+        ];
+        let chunks = chunks
+            .iter()
+            .map(|c| serde_json::from_str::<ChatCompletionChunk>(c))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let res = ChatCompletionResponse::from_chunks(chunks);
+        assert_eq!(res.id, "chatcmpl-1");
+        assert_eq!(res.object, "chat.completion");
+        assert_eq!(res.system_fingerprint.as_deref(), Some("fp_1"));
+        assert_eq!(res.choices.len(), 1);
+        let choice = &res.choices[0];
+        assert_eq!(choice.finish_reason, FinishReason::ToolCalls);
+        assert_eq!(choice.message.content, None);
+        let tool_call = &choice.message.tool_calls[0];
+        assert_eq!(tool_call.id, "call_1");
+        assert_eq!(tool_call.function.name, "get_weather_forecast");
+        assert_eq!(tool_call.function.arguments, r#"{"city":"ShangHai"}"#);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_completion_stream_should_work() -> Result<()> {
+        let server = MockServer::start().await;
+        let req = get_simple_completion_request();
+        let stream = server.sdk().chat_completion_stream(req).await?;
+        let res = ChatCompletionResponse::from_stream(stream).await?;
+
+        assert_eq!(res.model, ChatCompleteModel::GPT3_TURBO);
+        assert_eq!(res.choices.len(), 1);
+        let choice = &res.choices[0];
+        assert_eq!(choice.finish_reason, FinishReason::Stop);
+        assert_eq!(choice.message.content(), Some("Mock reply to: HI!"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_completion_stream_should_report_error_events() -> Result<()> {
+        let server = MockServer::start().await;
+        let chunk = serde_json::json!({
+            "id": "chatcmpl-mock",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "gpt-3.5-turbo",
+            "choices": [{ "index": 0, "delta": { "content": "Hi" }, "finish_reason": null }],
+        });
+        let error = serde_json::json!({
+            "error": { "message": "Overloaded", "type": "server_error", "param": null, "code": null },
+        });
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::events([chunk.clone(), chunk, error]),
+        );
+        let mut stream = server
+            .sdk()
+            .chat_completion_stream(get_simple_completion_request())
+            .await?;
+
+        assert!(stream.try_next().await?.is_some());
+        assert!(stream.try_next().await?.is_some());
+        let err = stream.try_next().await.unwrap_err();
+        let api = err.api_error().expect("an API error");
+        assert_eq!(api.r#type.as_deref(), Some("server_error"));
+        assert_eq!(api.message, "Overloaded");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mock_chat_completion_stream_should_work() -> Result<()> {
+        let server = MockServer::start().await;
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::chat_stream(["Hi ", "zheng!"]),
+        );
+        let stream = server
+            .sdk()
+            .chat_completion_stream(get_simple_completion_request())
+            .await?;
+        let res = ChatCompletionResponse::from_stream(stream).await?;
+
+        assert_eq!(res.choices[0].message.content(), Some("Hi zheng!"));
+        assert_eq!(res.choices[0].finish_reason, FinishReason::Stop);
+        assert_eq!(server.requests()[0].json().unwrap()["stream"], true);
 
         Ok(())
     }
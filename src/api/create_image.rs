@@ -1,7 +1,7 @@
 use derive_builder::Builder;
 use serde::{Deserialize, Serialize};
 
-use crate::IntoRequest;
+use crate::{model::model_id, Endpoint, IntoRequest};
 
 #[derive(Debug, Clone, Serialize, Builder)]
 #[builder(pattern = "mutable")]
@@ -12,7 +12,7 @@ pub struct CreateImageRequest {
     prompt: String,
     // The model to use for image generation.
     // 用于图像生成的模型。
-    #[builder(default)]
+    #[builder(default, setter(into))]
     model: ImageModel,
     // The number of images to generate. Must be between 1 and 10. For dall-e-3, only n=1 is supported.
     // 要生成的图像数量。必须介于 1 和 10 之间。对于 dall-e-3，仅支持 n=1。
@@ -41,11 +41,12 @@ pub struct CreateImageRequest {
     user: Option<String>,
 }
 
-#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
-pub enum ImageModel {
-    #[default]
-    #[serde(rename = "dall-e-3")]
-    DallE3,
+model_id! {
+    /// ID of an image model. Any id is accepted, the OpenAI models are available as constants.
+    ImageModel, default = DALL_E_3 {
+        DALL_E_2 = "dall-e-2",
+        DALL_E_3 = "dall-e-3",
+    }
 }
 
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
@@ -114,22 +115,23 @@ impl CreateImageRequest {
 }
 
 impl IntoRequest for CreateImageRequest {
-    fn into_request(self, client: reqwest::Client) -> reqwest::RequestBuilder {
-        let url = format!("{}{}", crate::SDK.base_url, "/images/generations");
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::Images
+    }
+
+    fn into_request(self, base_url: &str, client: reqwest::Client) -> reqwest::RequestBuilder {
+        let url = format!("{}{}", base_url, "/images/generations");
         client.post(url).json(&self)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs;
-
     use anyhow::{Ok, Result};
     use serde_json::json;
 
-    use crate::LLmSdk;
-
     use super::*;
+    use crate::testing::MockServer;
 
     #[test]
     fn create_image_request_should_serialize() -> Result<()> {
@@ -170,24 +172,17 @@ mod tests {
     }
 
     #[tokio::test]
-    #[ignore]
     async fn create_image_should_work() -> Result<()> {
-        let sdk = &crate::SDK;
+        let server = MockServer::start().await;
         let req = CreateImageRequest::new("draw a picture of a chicken eating rice");
-        let res = sdk.create_image(req).await?;
+        let res = server.sdk().create_image(req).await?;
         let image = &res.data[0];
 
-        assert_eq!(res.data.len(), 1);
-        assert!(image.url.is_none());
-        println!("image: {:?}", image);
-
-        fs::write(
-            "/tmp/llm-sdk/caterpillar.png",
-            reqwest::get(image.url.as_ref().unwrap())
-                .await?
-                .bytes()
-                .await?,
-        )?;
+        assert_eq!(image.url.as_deref(), Some("https://example.com/mock.png"));
+        assert_eq!(
+            image.revised_prompt,
+            "draw a picture of a chicken eating rice"
+        );
 
         Ok(())
     }
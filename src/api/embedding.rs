@@ -1,7 +1,9 @@
+use base64::{prelude::BASE64_STANDARD, Engine};
 use derive_builder::Builder;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::ops::AddAssign;
 
-use crate::IntoRequest;
+use crate::{model::model_id, Endpoint, IntoRequest, LlmError, Result, TokenCounter};
 
 #[derive(Debug, Clone, Serialize, Builder)]
 #[builder(pattern = "mutable")]
@@ -9,7 +11,7 @@ pub struct EmbeddingRequest {
     input: EmbeddingInput,
 
     /// ID of the model to use. You can use the List models API to see all of your available models, or see our Model overview for descriptions of them.
-    #[builder(default)]
+    #[builder(default, setter(into))]
     model: EmbeddingModel,
 
     /// The format to return the embeddings in. Can be either float or base64.
@@ -17,12 +19,32 @@ pub struct EmbeddingRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     encoding_format: Option<EmbeddingEncodingFormat>,
 
+    /// The number of dimensions of the embeddings, shortened by the server. Only supported by text-embedding-3 and later models.
+    #[builder(default, setter(strip_option))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dimensions: Option<usize>,
+
     /// A unique identifier representing your end-user, which can help OpenAI to monitor and detect abuse.
     #[builder(default, setter(strip_option, into))]
     #[serde(skip_serializing_if = "Option::is_none")]
     user: Option<String>,
 }
 
+/// How `LLmSdk::embed_many` splits its inputs into requests.
+#[derive(Debug, Clone, Builder)]
+#[builder(pattern = "mutable")]
+pub struct EmbeddingBatching {
+    /// The maximum number of inputs in a request.
+    #[builder(default = "2048")]
+    pub max_inputs: usize,
+    /// The maximum number of tokens of the inputs of a request. An input larger than this is sent alone.
+    #[builder(default = "300_000")]
+    pub max_tokens: usize,
+    /// The number of requests in flight at once.
+    #[builder(default = "4")]
+    pub concurrency: usize,
+}
+
 #[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
 pub enum EmbeddingEncodingFormat {
     #[default]
@@ -32,11 +54,30 @@ pub enum EmbeddingEncodingFormat {
     BASE64,
 }
 
-#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub enum EmbeddingModel {
-    #[default]
-    #[serde(rename = "text-embedding-ada-002")]
-    TextEmbeddingAda002,
+model_id! {
+    /// ID of an embedding model. Any id is accepted, the OpenAI models are available as constants.
+    EmbeddingModel, default = TEXT_EMBEDDING_ADA_002 {
+        TEXT_EMBEDDING_ADA_002 = "text-embedding-ada-002",
+        TEXT_EMBEDDING_3_SMALL = "text-embedding-3-small",
+        TEXT_EMBEDDING_3_LARGE = "text-embedding-3-large",
+    }
+}
+
+impl EmbeddingModel {
+    /// The size of the embeddings of the model, unless shortened with `dimensions`. `None` for unknown models.
+    pub fn dimensions(&self) -> Option<usize> {
+        match self.as_str() {
+            "text-embedding-ada-002" | "text-embedding-3-small" => Some(1536),
+            "text-embedding-3-large" => Some(3072),
+            _ => None,
+        }
+    }
+
+    /// Whether the model can shorten its embeddings to the `dimensions` of the request.
+    /// Assumed for unknown models, the server will tell.
+    pub fn supports_dimensions(&self) -> bool {
+        *self != Self::TEXT_EMBEDDING_ADA_002
+    }
 }
 
 #[allow(dead_code)]
@@ -54,11 +95,88 @@ impl EmbeddingRequest {
             .build()
             .unwrap()
     }
+
+    /// Check the `dimensions` against the model, before sending the request.
+    pub(crate) fn validate(&self) -> Result<()> {
+        let Some(dimensions) = self.dimensions else {
+            return Ok(());
+        };
+        if !self.model.supports_dimensions() {
+            return Err(LlmError::Validation(format!(
+                "{} doesn't support dimensions",
+                self.model
+            )));
+        }
+        let max = self.model.dimensions().unwrap_or(usize::MAX);
+        if dimensions == 0 || dimensions > max {
+            return Err(LlmError::Validation(format!(
+                "dimensions must be between 1 and {} for {}, got {}",
+                max, self.model, dimensions
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn input(&self) -> &EmbeddingInput {
+        &self.input
+    }
+
+    pub(crate) fn model(&self) -> &EmbeddingModel {
+        &self.model
+    }
+
+    /// Split the inputs into requests fitting `batching`, with the position of their first input.
+    pub(crate) fn split(self, batching: &EmbeddingBatching) -> Vec<(usize, EmbeddingRequest)> {
+        let EmbeddingInput::StringArray(inputs) = &self.input else {
+            return vec![(0, self)];
+        };
+        let counter = TokenCounter::for_model(&self.model);
+        let mut batches = vec![];
+        let mut start = 0;
+        let mut tokens = 0;
+        for (i, input) in inputs.iter().enumerate() {
+            let count = counter.count_text(input);
+            let full =
+                i - start >= batching.max_inputs.max(1) || tokens + count > batching.max_tokens;
+            if full && i > start {
+                batches.push((start, inputs[start..i].to_vec()));
+                start = i;
+                tokens = 0;
+            }
+            tokens += count;
+        }
+        if start < inputs.len() {
+            batches.push((start, inputs[start..].to_vec()));
+        }
+        batches
+            .into_iter()
+            .map(|(start, inputs)| {
+                let req = EmbeddingRequest {
+                    input: EmbeddingInput::StringArray(inputs),
+                    model: self.model.clone(),
+                    encoding_format: self.encoding_format.clone(),
+                    dimensions: self.dimensions,
+                    user: self.user.clone(),
+                };
+                (start, req)
+            })
+            .collect()
+    }
+}
+
+impl Default for EmbeddingBatching {
+    fn default() -> Self {
+        EmbeddingBatchingBuilder::default().build().unwrap()
+    }
 }
 
 impl IntoRequest for EmbeddingRequest {
-    fn into_request(self, client: reqwest::Client) -> reqwest::RequestBuilder {
-        let url = format!("{}{}", crate::SDK.base_url, "/embeddings");
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::Embeddings
+    }
+
+    fn into_request(self, base_url: &str, client: reqwest::Client) -> reqwest::RequestBuilder {
+        let url = format!("{}{}", base_url, "/embeddings");
         client.post(url).json(&self)
     }
 }
@@ -71,7 +189,7 @@ pub struct EmbeddingResponse {
     pub usage: EmbeddingUsage,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
 pub struct EmbeddingUsage {
     pub prompt_tokens: usize,
     pub total_tokens: usize,
@@ -85,11 +203,83 @@ pub struct EmbeddingData {
     #[serde(default)]
     index: usize,
     /// The embedding vector, which is a list of floats. The length of vector depends on the model as listed in the embedding guide.
+    /// Sent as base64 when asked with `EmbeddingEncodingFormat::BASE64`, and decoded here.
+    #[serde(deserialize_with = "floats_or_base64")]
     embedding: Vec<f32>,
     /// The object type, which is always "embedding".
     object: String,
 }
 
+impl EmbeddingData {
+    /// The position of the input this embedding belongs to.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub(crate) fn shift_index(&mut self, offset: usize) {
+        self.index += offset;
+    }
+
+    pub fn embedding(&self) -> &[f32] {
+        &self.embedding
+    }
+
+    pub fn into_embedding(self) -> Vec<f32> {
+        self.embedding
+    }
+}
+
+impl EmbeddingInput {
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            EmbeddingInput::String(_) => 1,
+            EmbeddingInput::StringArray(inputs) => inputs.len(),
+        }
+    }
+}
+
+/// Encode `floats` the way the API sends base64 embeddings: little-endian f32s.
+pub(crate) fn encode_f32s(floats: &[f32]) -> String {
+    let bytes: Vec<u8> = floats.iter().flat_map(|x| x.to_le_bytes()).collect();
+    BASE64_STANDARD.encode(bytes)
+}
+
+pub(crate) fn decode_f32s(data: &str) -> Option<Vec<f32>> {
+    let bytes = BASE64_STANDARD.decode(data).ok()?;
+    if bytes.len() % 4 != 0 {
+        return None;
+    }
+    Some(
+        bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+    )
+}
+
+fn floats_or_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<f32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Embedding {
+        Floats(Vec<f32>),
+        Base64(String),
+    }
+
+    match Embedding::deserialize(deserializer)? {
+        Embedding::Floats(floats) => Ok(floats),
+        Embedding::Base64(data) => {
+            decode_f32s(&data).ok_or_else(|| de::Error::custom("invalid base64 embedding"))
+        }
+    }
+}
+
+impl AddAssign for EmbeddingUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
 impl From<Vec<String>> for EmbeddingInput {
     fn from(value: Vec<String>) -> Self {
         EmbeddingInput::StringArray(value)
@@ -118,18 +308,24 @@ impl From<&str> for EmbeddingInput {
 mod tests {
 
     use super::*;
+    use crate::{
+        testing::{mock_embedding, MockResponse, MockServer, MOCK_EMBEDDING_SIZE},
+        LLmSdk, RetryPolicy,
+    };
     use anyhow::{Ok, Result};
+    use reqwest::StatusCode;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn embeddings_should_work() -> Result<()> {
-        let sdk = &crate::SDK;
+        let server = MockServer::start().await;
         let req = EmbeddingRequest::new("The food was delicious and the waiter...");
-        let res = sdk.embedding(req).await?;
+        let res = server.sdk().embedding(req).await?;
         assert_eq!(res.data.len(), 1);
         assert_eq!(res.object, "list");
 
         let data = &res.data[0];
-        assert_eq!(data.embedding.len(), 1536);
+        assert_eq!(data.embedding.len(), MOCK_EMBEDDING_SIZE);
         assert_eq!(data.index, 0);
         assert_eq!(data.object, "embedding");
 
@@ -138,19 +334,144 @@ mod tests {
 
     #[tokio::test]
     async fn embeddings_input_array_should_work() -> Result<()> {
-        let sdk = &crate::SDK;
+        let server = MockServer::start().await;
         let req = EmbeddingRequest::new(vec![
             "The quick brown fox jumped over the lazy dog.".into(),
             "我是谁？我在哪？".into(),
         ]);
-        let res = sdk.embedding(req).await?;
+        let res = server.sdk().embedding(req).await?;
         assert_eq!(res.data.len(), 2);
         assert_eq!(res.object, "list");
         let data = &res.data[1];
-        assert_eq!(data.embedding.len(), 1536);
+        assert_eq!(data.embedding, mock_embedding("我是谁？我在哪？"));
         assert_eq!(data.index, 1);
         assert_eq!(data.object, "embedding");
 
+        let req = server.requests()[0].json().unwrap();
+        assert_eq!(req["input"].as_array().unwrap().len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn embedding_data_should_decode_base64() {
+        let floats = [0.5f32, -1.25, 3.0];
+        let body = format!(
+            r#"{{"index":2,"embedding":"{}","object":"embedding"}}"#,
+            encode_f32s(&floats)
+        );
+        let data: EmbeddingData = serde_json::from_str(&body).unwrap();
+        assert_eq!(data.index(), 2);
+        assert_eq!(data.embedding(), floats);
+
+        let body = r#"{"index":0,"embedding":"AAA=","object":"embedding"}"#;
+        assert!(serde_json::from_str::<EmbeddingData>(body).is_err());
+    }
+
+    #[test]
+    fn dimensions_should_be_validated_against_model() {
+        let req = |model: EmbeddingModel, dimensions| {
+            EmbeddingRequestBuilder::default()
+                .input("hello".into())
+                .model(model)
+                .dimensions(dimensions)
+                .build()
+                .unwrap()
+        };
+        assert!(req(EmbeddingModel::TEXT_EMBEDDING_3_SMALL, 256)
+            .validate()
+            .is_ok());
+        assert!(req(EmbeddingModel::TEXT_EMBEDDING_3_LARGE, 3072)
+            .validate()
+            .is_ok());
+        assert!(req(EmbeddingModel::TEXT_EMBEDDING_3_SMALL, 3072)
+            .validate()
+            .is_err());
+        assert!(req(EmbeddingModel::TEXT_EMBEDDING_ADA_002, 256)
+            .validate()
+            .is_err());
+        assert!(req(EmbeddingModel::new("custom"), 4096).validate().is_ok());
+        assert_eq!(
+            EmbeddingModel::TEXT_EMBEDDING_3_LARGE.dimensions(),
+            Some(3072)
+        );
+    }
+
+    #[tokio::test]
+    async fn mock_embeddings_base64_with_dimensions_should_work() -> Result<()> {
+        let server = MockServer::start().await;
+        let req = EmbeddingRequestBuilder::default()
+            .input("The quick brown fox.".into())
+            .model(EmbeddingModel::TEXT_EMBEDDING_3_SMALL)
+            .encoding_format(EmbeddingEncodingFormat::BASE64)
+            .dimensions(8)
+            .build()?;
+        let res = server.sdk().embedding(req).await?;
+        assert_eq!(
+            server.requests()[0].json().unwrap()["encoding_format"],
+            "base64"
+        );
+
+        let embedding = res.data[0].embedding();
+        assert_eq!(embedding.len(), 8);
+        let norm: f32 = embedding.iter().map(|x| x * x).sum();
+        assert!((norm - 1.0).abs() < 1e-5);
+        Ok(())
+    }
+
+    #[test]
+    fn split_should_respect_count_and_tokens() {
+        let inputs: Vec<String> = ["a", "b", "c", "d", "e"].map(String::from).to_vec();
+        let batching = EmbeddingBatchingBuilder::default()
+            .max_inputs(2)
+            .build()
+            .unwrap();
+        let batches = EmbeddingRequest::new(inputs.clone()).split(&batching);
+        let starts: Vec<_> = batches.iter().map(|b| b.0).collect();
+        assert_eq!(starts, [0, 2, 4]);
+        assert_eq!(batches[2].1.input().len(), 1);
+
+        let long = "word ".repeat(100);
+        let inputs = vec!["a".into(), long.clone(), "b".into(), "c".into()];
+        let batching = EmbeddingBatchingBuilder::default()
+            .max_tokens(50)
+            .build()
+            .unwrap();
+        let batches = EmbeddingRequest::new(inputs).split(&batching);
+        let sizes: Vec<_> = batches.iter().map(|b| (b.0, b.1.input().len())).collect();
+        assert_eq!(sizes, [(0, 1), (1, 1), (2, 2)]);
+
+        assert_eq!(EmbeddingRequest::new(long).split(&batching).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn embed_many_should_reassemble_batches_in_order() -> Result<()> {
+        let server = MockServer::start().await;
+        server.enqueue(
+            Endpoint::Embeddings,
+            MockResponse::error(StatusCode::SERVICE_UNAVAILABLE, "Overloaded"),
+        );
+        let inputs: Vec<String> = (0..7).map(|i| format!("input number {}", i)).collect();
+        let batching = EmbeddingBatchingBuilder::default()
+            .max_inputs(3)
+            .concurrency(2)
+            .build()
+            .unwrap();
+        let retry = RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        let res = LLmSdk::new(server.url(), "")
+            .with_retry_policy(retry)
+            .embed_many(EmbeddingRequest::new(inputs.clone()), &batching)
+            .await?;
+
+        assert_eq!(server.requests().len(), 4);
+        assert_eq!(res.data.len(), 7);
+        for (i, data) in res.data.iter().enumerate() {
+            assert_eq!(data.index, i);
+            assert_eq!(data.embedding, mock_embedding(&inputs[i]));
+        }
         Ok(())
     }
 }
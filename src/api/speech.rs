@@ -1,11 +1,12 @@
-use crate::IntoRequest;
+use crate::{model::model_id, Endpoint, IntoRequest};
 use derive_builder::Builder;
 use serde::Serialize;
 
 #[derive(Debug, Clone, Serialize, Builder)]
 #[builder(pattern = "mutable")]
 pub struct SpeechRequest {
-    #[builder(default)]
+    /// ID of the model to use, tts-1 or tts-1-hd.
+    #[builder(default, setter(into))]
     model: SpeechModel,
     /// The text to generate audio for. The maximum length is 4096 characters.
     #[builder(setter(into))]
@@ -46,14 +47,12 @@ pub enum SpeechVoice {
     Shimmer,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Default, Serialize)]
-pub enum SpeechModel {
-    #[default]
-    #[serde(rename = "tts-1")]
-    Tts1,
-    #[serde(rename = "tts-1-hd")]
-    Tts1Hd,
+model_id! {
+    /// ID of a text to speech model. Any id is accepted, the OpenAI models are available as constants.
+    SpeechModel, default = TTS_1 {
+        TTS_1 = "tts-1",
+        TTS_1_HD = "tts-1-hd",
+    }
 }
 
 impl SpeechRequest {
@@ -66,26 +65,31 @@ impl SpeechRequest {
 }
 
 impl IntoRequest for SpeechRequest {
-    fn into_request(self, client: reqwest::Client) -> reqwest::RequestBuilder {
-        let url = format!("{}{}", crate::SDK.base_url, "/audio/speech");
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::Speech
+    }
+
+    fn into_request(self, base_url: &str, client: reqwest::Client) -> reqwest::RequestBuilder {
+        let url = format!("{}{}", base_url, "/audio/speech");
         client.post(url).json(&self)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::fs;
-
     use super::*;
+    use crate::testing::MockServer;
     use anyhow::Result;
 
     #[tokio::test]
     async fn speech_should_work() -> Result<()> {
-        let sdk = &crate::SDK;
+        let server = MockServer::start().await;
         let req = SpeechRequest::new("The quick brown fox jumped over the lazy dog");
-        let res = sdk.speech(req).await?;
+        let res = server.sdk().speech(req).await?;
+        assert_eq!(res, "mock audio");
 
-        fs::write("/tmp/qbot/test.mp3", res)?;
+        let req = server.requests()[0].json().unwrap();
+        assert_eq!(req["input"], "The quick brown fox jumped over the lazy dog");
 
         Ok(())
     }
@@ -1,6 +1,6 @@
-use crate::{IntoRequest, SDK};
+use crate::{cassette::fnv1a, model::model_id, Endpoint, IntoRequest};
 use derive_builder::Builder;
-use reqwest::multipart::{Form, Part};
+use reqwest::header::CONTENT_TYPE;
 use serde::{Deserialize, Serialize};
 use strum_macros::Display;
 
@@ -9,7 +9,7 @@ pub struct WhisperRequest {
     /// The audio file object (not file name) to transcribe, in one of these formats: flac, mp3, mp4, mpeg, mpga, m4a, ogg, wav, or webm.
     pub file: Vec<u8>,
     /// ID of the model to use. Only whisper-1 is currently available.
-    #[builder(default)]
+    #[builder(default, setter(into))]
     pub model: WhisperModel,
     /// The language of the input audio. Supplying the input language in ISO-639-1 format will improve accuracy and latency.
     #[builder(default, setter(strip_option, into))]
@@ -49,12 +49,11 @@ pub enum WhisperResponseFormat {
     Vtt,
 }
 
-#[derive(Debug, Clone, Default, Serialize, Display)]
-pub enum WhisperModel {
-    #[serde(rename = "whisper-1")]
-    #[strum(serialize = "whisper-1")]
-    #[default]
-    Whisper1,
+model_id! {
+    /// ID of a speech to text model. Any id is accepted, the OpenAI models are available as constants.
+    WhisperModel, default = WHISPER_1 {
+        WHISPER_1 = "whisper-1",
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Builder)]
@@ -79,40 +78,94 @@ impl WhisperRequest {
             .unwrap()
     }
 
-    fn into_form(self) -> Form {
-        let part = Part::bytes(self.file)
-            .file_name("file")
-            .mime_str("audio/mp3")
-            .unwrap();
-
-        let mut form = Form::new()
-            .part("file", part)
-            .text("model", self.model.to_string())
-            .text("response_format", self.response_format.to_string())
-            .text("prompt", self.prompt.unwrap_or_default())
-            .text(
+    /// Encode the multipart form as plain bytes rather than with `reqwest::multipart`,
+    /// whose streamed body can't be read back by a `Cassette`. Returns the boundary and the body.
+    fn into_body(self) -> (String, Vec<u8>) {
+        let mut fields = vec![
+            ("model", self.model.to_string()),
+            ("response_format", self.response_format.to_string()),
+            ("prompt", self.prompt.unwrap_or_default()),
+            (
                 "temperature",
                 self.temperature
                     .map_or_else(|| "".to_string(), |temp| temp.to_string()),
-            );
+            ),
+        ];
+        if let (WhisperRequestType::Transcription, Some(language)) =
+            (&self.request_type, self.language)
+        {
+            fields.push(("language", language));
+        }
 
-        if self.request_type == WhisperRequestType::Transcription && self.language.is_some() {
-            form = form.text("language", self.language.unwrap());
+        let boundary = form_boundary(&self.file, &fields);
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"file\"\r\nContent-Type: audio/mp3\r\n\r\n",
+            boundary
+        )
+        .into_bytes();
+        body.extend(self.file);
+        body.extend(b"\r\n");
+        for (name, value) in fields {
+            body.extend(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                    boundary, name, value
+                )
+                .as_bytes(),
+            );
         }
+        body.extend(format!("--{}--\r\n", boundary).as_bytes());
+        (boundary, body)
+    }
+}
 
-        form
+/// A boundary found in none of the parts. It's derived from their content, so the same request
+/// always gets the same body and can be matched by a `Cassette`.
+fn form_boundary(file: &[u8], fields: &[(&str, String)]) -> String {
+    let mut data = file.to_vec();
+    for (name, value) in fields {
+        data.extend(name.as_bytes());
+        data.extend(value.as_bytes());
     }
+    (0u64..)
+        .map(|salt| {
+            let hash = fnv1a(&[&data[..], &salt.to_le_bytes()].concat());
+            format!("q-bot-boundary-{:016x}", hash)
+        })
+        .find(|boundary| {
+            let boundary = boundary.as_bytes();
+            !contains(file, boundary)
+                && fields
+                    .iter()
+                    .all(|(_, value)| !contains(value.as_bytes(), boundary))
+        })
+        .unwrap()
+}
+
+fn contains(data: &[u8], needle: &[u8]) -> bool {
+    data.windows(needle.len()).any(|w| w == needle)
 }
 
 impl IntoRequest for WhisperRequest {
-    fn into_request(self, client: reqwest::Client) -> reqwest::RequestBuilder {
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::Whisper
+    }
+
+    fn into_request(self, base_url: &str, client: reqwest::Client) -> reqwest::RequestBuilder {
         let api_url = if self.request_type == WhisperRequestType::Translation {
-            format!("{}{}", SDK.base_url, "/audio/translations")
+            format!("{}{}", base_url, "/audio/translations")
         } else {
-            format!("{}{}", &SDK.base_url, "/audio/transcriptions")
+            format!("{}{}", base_url, "/audio/transcriptions")
         };
 
-        client.post(api_url).multipart(self.into_form())
+        let (boundary, body) = self.into_body();
+        client
+            .post(api_url)
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(body)
     }
 }
 
@@ -121,48 +174,87 @@ mod test {
     use std::fs;
 
     use super::*;
+    use crate::testing::{MockResponse, MockServer, MOCK_TRANSCRIPTION};
     use anyhow::Result;
 
     #[tokio::test]
     async fn transcription_should_work() -> Result<()> {
-        let sdk = &crate::SDK;
+        let server = MockServer::start().await;
         let stream = fs::read("fixtures/test.mp3")?;
         let req = WhisperRequest::transcription(stream);
-        let res = sdk.whisper(req).await?;
-        fs::write("res.xtx", &res.text);
-        assert_eq!(res.text, "The quick brown fox jumped over the lazy dog.");
+        let res = server.sdk().whisper(req).await?;
+        assert_eq!(res.text, MOCK_TRANSCRIPTION);
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/audio/transcriptions");
+        assert_eq!(
+            requests[0].form_field("model").as_deref(),
+            Some("whisper-1")
+        );
 
         Ok(())
     }
 
+    #[test]
+    fn form_boundary_should_be_absent_from_the_parts() {
+        let prompt = "ignore this\r\n--q-bot-boundary-";
+        let req = WhisperRequestBuilder::default()
+            .file(b"audio".to_vec())
+            .prompt(prompt)
+            .build()
+            .unwrap();
+        let (boundary, body) = req.clone().into_body();
+        assert_eq!(req.into_body(), (boundary.clone(), body.clone()));
+
+        let body = String::from_utf8(body).unwrap();
+        assert!(!prompt.contains(&boundary));
+        // the file, four fields and the closing boundary
+        assert_eq!(body.matches(&format!("--{}", boundary)).count(), 6);
+    }
+
     #[tokio::test]
     async fn transcription_with_response_should_work() -> Result<()> {
-        let sdk = &crate::SDK;
+        let server = MockServer::start().await;
+        server.enqueue(
+            Endpoint::Whisper,
+            MockResponse::text("The quick brown fox jumped over the lazy dog.\n"),
+        );
         let stream = fs::read("fixtures/test.mp3")?;
         let req = WhisperRequestBuilder::default()
             .file(stream)
             .response_format(WhisperResponseFormat::Text)
             .build()?;
-        let res = sdk.whisper(req).await?;
+        let res = server.sdk().whisper(req).await?;
         assert_eq!(res.text, "The quick brown fox jumped over the lazy dog.\n");
+        assert_eq!(
+            server.requests()[0]
+                .form_field("response_format")
+                .as_deref(),
+            Some("text")
+        );
 
         Ok(())
     }
 
     #[tokio::test]
     async fn transcription_with_request_type_should_work() -> Result<()> {
-        let sdk = &crate::SDK;
+        let srt = "1\n00:00:00,000 --> 00:00:02,000\n欢迎来到爱泽拉斯 Welcome to愛泽拉斯\n\n\n";
+        let server = MockServer::start().await;
+        server.enqueue(Endpoint::Whisper, MockResponse::text(srt));
         let stream = fs::read("fixtures/wow.mp3")?;
         let req = WhisperRequestBuilder::default()
             .file(stream)
             .response_format(WhisperResponseFormat::Srt)
             .request_type(WhisperRequestType::Translation)
             .build()?;
-        let res = sdk.whisper(req).await?;
-        fs::write("res.txt", &res.text);
+        let res = server.sdk().whisper(req).await?;
+        assert_eq!(res.text, srt);
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/audio/translations");
         assert_eq!(
-            res.text,
-            "1\n00:00:00,000 --> 00:00:02,000\n欢迎来到爱泽拉斯 Welcome to愛泽拉斯\n\n\n"
+            requests[0].form_field("response_format").as_deref(),
+            Some("srt")
         );
 
         Ok(())
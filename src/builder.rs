@@ -0,0 +1,258 @@
+use crate::{
+    Cassette, Endpoint, LLmSdk, LlmError, Middleware, MiddlewareChain, ModelInfo, ModelRegistry,
+    Result, RetryPolicy, DEFAULT_BASE_URL, DEFAULT_TIMEOUT,
+};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT},
+    Client, Proxy,
+};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+/// Builder for `LLmSdk`, for everything `LLmSdk::new` doesn't let you configure.
+#[derive(Debug, Clone, Default)]
+pub struct LLmSdkBuilder {
+    base_url: Option<String>,
+    token: Option<String>,
+    timeout: Option<Duration>,
+    endpoint_timeouts: HashMap<Endpoint, Duration>,
+    connect_timeout: Option<Duration>,
+    proxy: Option<String>,
+    headers: Vec<(String, String)>,
+    organization: Option<String>,
+    project: Option<String>,
+    user_agent: Option<String>,
+    client: Option<Client>,
+    retry: Option<RetryPolicy>,
+    models: Vec<ModelInfo>,
+    cassette: Option<Arc<Cassette>>,
+    middlewares: MiddlewareChain,
+}
+
+impl LLmSdkBuilder {
+    /// Base url of the API. Defaults to `https://api.openai.com/v1`.
+    pub fn base_url(&mut self, base_url: impl Into<String>) -> &mut Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+
+    /// Bearer token sent with every request. No `Authorization` header is sent if empty.
+    pub fn token(&mut self, token: impl Into<String>) -> &mut Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Timeout of a whole request, for endpoints without their own timeout. Defaults to 30s.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Timeout of a whole request to `endpoint`, overriding `timeout`.
+    pub fn endpoint_timeout(&mut self, endpoint: Endpoint, timeout: Duration) -> &mut Self {
+        self.endpoint_timeouts.insert(endpoint, timeout);
+        self
+    }
+
+    /// Timeout of the connect phase. Can't be combined with `client`.
+    pub fn connect_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Proxy all requests through `proxy`, e.g. `http://127.0.0.1:7890`. Can't be combined with `client`.
+    pub fn proxy(&mut self, proxy: impl Into<String>) -> &mut Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
+
+    /// Extra header sent with every request.
+    pub fn header(&mut self, name: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sent as the `OpenAI-Organization` header.
+    pub fn organization(&mut self, organization: impl Into<String>) -> &mut Self {
+        self.organization = Some(organization.into());
+        self
+    }
+
+    /// Sent as the `OpenAI-Project` header.
+    pub fn project(&mut self, project: impl Into<String>) -> &mut Self {
+        self.project = Some(project.into());
+        self
+    }
+
+    pub fn user_agent(&mut self, user_agent: impl Into<String>) -> &mut Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Send requests with a pre-configured client instead of building one.
+    pub fn client(&mut self, client: Client) -> &mut Self {
+        self.client = Some(client);
+        self
+    }
+
+    pub fn retry_policy(&mut self, retry: RetryPolicy) -> &mut Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Register the metadata of a model the sdk doesn't know, e.g. one served by an OpenAI-compatible server.
+    pub fn model(&mut self, info: ModelInfo) -> &mut Self {
+        self.models.push(info);
+        self
+    }
+
+    /// Record the requests to `cassette`, or replay them from it.
+    pub fn cassette(&mut self, cassette: Cassette) -> &mut Self {
+        self.cassette = Some(Arc::new(cassette));
+        self
+    }
+
+    /// Add `middleware` to the end of the chain every request goes through. See `Middleware` for the order.
+    pub fn middleware(&mut self, middleware: impl Middleware + 'static) -> &mut Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    pub fn build(&self) -> Result<LLmSdk> {
+        let mut headers = HeaderMap::new();
+        let named = [
+            ("OpenAI-Organization", &self.organization),
+            ("OpenAI-Project", &self.project),
+        ];
+        let named = named
+            .into_iter()
+            .filter_map(|(name, value)| value.as_ref().map(|v| (name, v.as_str())));
+        let custom = self.headers.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        for (name, value) in named.chain(custom) {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| LlmError::Config(format!("invalid header name {}: {}", name, e)))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|e| LlmError::Config(format!("invalid value for {}: {}", name, e)))?;
+            headers.insert(name, value);
+        }
+        if let Some(user_agent) = &self.user_agent {
+            let value = HeaderValue::from_str(user_agent)
+                .map_err(|e| LlmError::Config(format!("invalid user agent: {}", e)))?;
+            headers.insert(USER_AGENT, value);
+        }
+
+        let client = match &self.client {
+            Some(_) if self.proxy.is_some() || self.connect_timeout.is_some() => {
+                return Err(LlmError::Config(
+                    "proxy and connect timeout must be set on the injected client".into(),
+                ));
+            }
+            Some(client) => client.clone(),
+            None => {
+                let mut builder = Client::builder();
+                if let Some(timeout) = self.connect_timeout {
+                    builder = builder.connect_timeout(timeout);
+                }
+                if let Some(proxy) = &self.proxy {
+                    let proxy = Proxy::all(proxy)
+                        .map_err(|e| LlmError::Config(format!("invalid proxy: {}", e)))?;
+                    builder = builder.proxy(proxy);
+                }
+                builder
+                    .build()
+                    .map_err(|e| LlmError::Config(format!("failed to build client: {}", e)))?
+            }
+        };
+
+        let mut models = ModelRegistry::default();
+        for info in &self.models {
+            models.register(info.clone());
+        }
+
+        Ok(LLmSdk {
+            base_url: self
+                .base_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+            token: self.token.clone().unwrap_or_default(),
+            client,
+            retry: self.retry.clone().unwrap_or_default(),
+            headers,
+            timeout: self.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            endpoint_timeouts: self.endpoint_timeouts.clone(),
+            models,
+            cassette: self.cassette.clone(),
+            middlewares: self.middlewares.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EmbeddingRequest, WhisperRequest};
+
+    #[test]
+    fn builder_should_apply_headers_and_timeouts() -> Result<()> {
+        let sdk = LLmSdkBuilder::default()
+            .base_url("http://localhost:8080/v1")
+            .token("token")
+            .organization("org-1")
+            .project("proj-1")
+            .user_agent("q-bot/0.1")
+            .header("x-gateway-key", "secret")
+            .timeout(Duration::from_secs(10))
+            .endpoint_timeout(Endpoint::Whisper, Duration::from_secs(120))
+            .connect_timeout(Duration::from_secs(3))
+            .build()?;
+
+        let req = sdk
+            .prepare_request(EmbeddingRequest::new("hello"))
+            .build()?;
+        let headers = req.headers();
+        assert_eq!(headers["openai-organization"], "org-1");
+        assert_eq!(headers["openai-project"], "proj-1");
+        assert_eq!(headers["user-agent"], "q-bot/0.1");
+        assert_eq!(headers["x-gateway-key"], "secret");
+        assert_eq!(headers["authorization"], "Bearer token");
+        assert_eq!(req.timeout(), Some(&Duration::from_secs(10)));
+
+        let req = sdk
+            .prepare_request(WhisperRequest::transcription(vec![]))
+            .build()?;
+        assert_eq!(req.timeout(), Some(&Duration::from_secs(120)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn builder_should_register_models() -> Result<()> {
+        let sdk = LLmSdkBuilder::default()
+            .model(
+                crate::ModelInfoBuilder::default()
+                    .id("llama3")
+                    .context_window(8192)
+                    .build()
+                    .unwrap(),
+            )
+            .build()?;
+        assert_eq!(sdk.models().get("llama3").unwrap().context_window, 8192);
+        assert!(sdk.models().get("gpt-4o").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn builder_should_reject_bad_config() {
+        let err = LLmSdkBuilder::default()
+            .header("bad header", "value")
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, LlmError::Config(_)));
+
+        let err = LLmSdkBuilder::default()
+            .client(Client::new())
+            .proxy("http://127.0.0.1:7890")
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, LlmError::Config(_)));
+    }
+}
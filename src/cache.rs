@@ -0,0 +1,477 @@
+use crate::{cassette::fnv1a, Endpoint, LlmError, Middleware, Next, Result};
+use async_trait::async_trait;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use reqwest::{
+    header::{HeaderName, HeaderValue, CONTENT_TYPE},
+    Request, Response,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A middleware caching the responses of deterministic requests: embeddings, speech, and chat completions
+/// with `temperature` 0 that aren't streamed. Other requests, and non-2xx responses, are never cached.
+///
+/// Requests are keyed by their endpoint, url and body. Clones share the same cache and mode, so keep one
+/// to change the mode after adding it with `LLmSdk::with_middleware`.
+#[derive(Clone)]
+pub struct ResponseCache {
+    backend: Arc<dyn CacheBackend>,
+    ttl: Option<Duration>,
+    mode: Arc<Mutex<CacheMode>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Answer from the cache, and cache new responses.
+    #[default]
+    Enabled,
+    /// Always send the requests, and cache their responses.
+    Refresh,
+    /// Always send the requests, and don't cache their responses.
+    Bypass,
+}
+
+/// Where `ResponseCache` keeps the responses.
+#[async_trait]
+pub trait CacheBackend: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<CachedResponse>>;
+    async fn put(&self, key: &str, response: CachedResponse) -> Result<()>;
+    async fn remove(&self, key: &str) -> Result<()>;
+}
+
+/// A cached response, with its body decoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    body: Vec<u8>,
+    /// Milliseconds since the unix epoch.
+    created_at: u64,
+}
+
+/// An in-memory cache, evicting the least recently used response beyond `capacity` responses.
+#[derive(Debug)]
+pub struct MemoryCache {
+    capacity: usize,
+    state: Mutex<Lru>,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<String, (CachedResponse, u64)>,
+    /// The keys by last use.
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+/// An on-disk cache, with one json file per response in `dir`.
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl ResponseCache {
+    pub fn new(backend: impl CacheBackend + 'static) -> Self {
+        Self {
+            backend: Arc::new(backend),
+            ttl: None,
+            mode: Arc::default(),
+        }
+    }
+
+    /// A `MemoryCache` of `capacity` responses.
+    pub fn memory(capacity: usize) -> Self {
+        Self::new(MemoryCache::new(capacity))
+    }
+
+    /// A `DiskCache` in `dir`.
+    pub fn disk(dir: impl Into<PathBuf>) -> Self {
+        Self::new(DiskCache::new(dir))
+    }
+
+    /// Expire responses older than `ttl`. They never expire by default.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn mode(&self) -> CacheMode {
+        *self.mode.lock().unwrap()
+    }
+
+    pub fn set_mode(&self, mode: CacheMode) {
+        *self.mode.lock().unwrap() = mode;
+    }
+
+    /// The key of `req`, if its response can be cached.
+    fn key(endpoint: Endpoint, req: &Request) -> Option<String> {
+        let body = req.body().and_then(|b| b.as_bytes()).unwrap_or_default();
+        let cacheable = match endpoint {
+            Endpoint::Embeddings | Endpoint::Speech => true,
+            Endpoint::ChatCompletions => {
+                let body: Value = serde_json::from_slice(body).ok()?;
+                body["stream"] != true && body["temperature"].as_f64() == Some(0.0)
+            }
+            Endpoint::Images | Endpoint::Whisper => false,
+        };
+        let mut data = format!("{} {}\n", req.method(), req.url()).into_bytes();
+        data.extend(body);
+        cacheable.then(|| format!("{:?}-{:016x}", endpoint, fnv1a(&data)).to_lowercase())
+    }
+
+    async fn lookup(&self, key: &str) -> Result<Option<Response>> {
+        let Some(cached) = self.backend.get(key).await? else {
+            return Ok(None);
+        };
+        let expired = self.ttl.is_some_and(|ttl| {
+            let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+            now() >= cached.created_at.saturating_add(ttl)
+        });
+        if expired {
+            self.backend.remove(key).await?;
+            return Ok(None);
+        }
+        cached.to_response().map(Some)
+    }
+}
+
+#[async_trait]
+impl Middleware for ResponseCache {
+    async fn handle(&self, endpoint: Endpoint, req: Request, next: Next<'_>) -> Result<Response> {
+        let mode = self.mode();
+        let key = match mode {
+            CacheMode::Bypass => None,
+            _ => Self::key(endpoint, &req),
+        };
+        let Some(key) = key else {
+            return next.run(endpoint, req).await;
+        };
+        if mode == CacheMode::Enabled {
+            match self.lookup(&key).await {
+                Ok(Some(res)) => {
+                    tracing::debug!("answering {:?} from the cache", endpoint);
+                    return Ok(res);
+                }
+                Ok(None) => {}
+                Err(e) => tracing::warn!("cache lookup failed, sending the request: {}", e),
+            }
+        }
+
+        let res = next.run(endpoint, req).await?;
+        if !res.status().is_success() {
+            return Ok(res);
+        }
+        let cached = CachedResponse::read(res).await?;
+        let res = cached.to_response()?;
+        // a response that can't be cached is still a good response
+        if let Err(e) = self.backend.put(&key, cached).await {
+            tracing::warn!("failed to cache the response: {}", e);
+        }
+        Ok(res)
+    }
+
+    fn name(&self) -> &str {
+        "ResponseCache"
+    }
+}
+
+impl fmt::Debug for ResponseCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseCache")
+            .field("ttl", &self.ttl)
+            .field("mode", &self.mode())
+            .finish()
+    }
+}
+
+impl CachedResponse {
+    async fn read(res: Response) -> Result<Self> {
+        let status = res.status().as_u16();
+        let headers = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| vec![(CONTENT_TYPE.to_string(), v.to_string())])
+            .unwrap_or_default();
+        let body = res.bytes().await?.to_vec();
+        Ok(Self {
+            status,
+            headers,
+            body,
+            created_at: now(),
+        })
+    }
+
+    fn to_response(&self) -> Result<Response> {
+        let mut res = http::Response::builder().status(self.status);
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                res = res.header(name, value);
+            }
+        }
+        let res = res
+            .body(self.body.clone())
+            .map_err(|e| LlmError::Storage(format!("invalid cached response: {}", e)))?;
+        Ok(res.into())
+    }
+}
+
+impl MemoryCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::default(),
+        }
+    }
+}
+
+impl Lru {
+    fn touch(&mut self, key: &str) -> Option<&CachedResponse> {
+        self.tick += 1;
+        let (response, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        *used = self.tick;
+        self.order.insert(self.tick, key.to_string());
+        Some(response)
+    }
+}
+
+#[async_trait]
+impl CacheBackend for MemoryCache {
+    async fn get(&self, key: &str) -> Result<Option<CachedResponse>> {
+        Ok(self.state.lock().unwrap().touch(key).cloned())
+    }
+
+    async fn put(&self, key: &str, response: CachedResponse) -> Result<()> {
+        let mut lru = self.state.lock().unwrap();
+        lru.tick += 1;
+        let tick = lru.tick;
+        if let Some((_, used)) = lru.entries.insert(key.to_string(), (response, tick)) {
+            lru.order.remove(&used);
+        }
+        lru.order.insert(tick, key.to_string());
+        while lru.entries.len() > self.capacity {
+            let Some((_, oldest)) = lru.order.pop_first() else {
+                break;
+            };
+            lru.entries.remove(&oldest);
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        let mut lru = self.state.lock().unwrap();
+        if let Some((_, used)) = lru.entries.remove(key) {
+            lru.order.remove(&used);
+        }
+        Ok(())
+    }
+}
+
+impl DiskCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+#[async_trait]
+impl CacheBackend for DiskCache {
+    async fn get(&self, key: &str) -> Result<Option<CachedResponse>> {
+        match tokio::fs::read(self.path(key)).await {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+
+    async fn put(&self, key: &str, response: CachedResponse) -> Result<()> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(io_error)?;
+        let path = self.path(key);
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(&response)?)
+            .await
+            .map_err(io_error)?;
+        tokio::fs::rename(&tmp, &path).await.map_err(io_error)
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(io_error(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn io_error(e: io::Error) -> LlmError {
+    LlmError::Storage(e.to_string())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn to_base64<S: Serializer>(body: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&BASE64_STANDARD.encode(body))
+}
+
+fn from_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let data = String::deserialize(deserializer)?;
+    BASE64_STANDARD
+        .decode(data)
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{temp_path, MockServer},
+        ChatCompletionMessage, ChatCompletionRequest, ChatCompletionRequestBuilder,
+        EmbeddingRequest, SpeechRequest,
+    };
+
+    fn response(body: &str) -> CachedResponse {
+        CachedResponse {
+            status: 200,
+            headers: vec![],
+            body: body.into(),
+            created_at: now(),
+        }
+    }
+
+    fn chat_request(temperature: Option<f32>) -> ChatCompletionRequest {
+        let mut req = ChatCompletionRequestBuilder::default();
+        req.messages(vec![ChatCompletionMessage::new_user("Hello!", "")]);
+        if let Some(temperature) = temperature {
+            req.temperature(temperature);
+        }
+        req.build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn memory_cache_should_evict_least_recently_used() -> Result<()> {
+        let cache = MemoryCache::new(2);
+        cache.put("a", response("a")).await?;
+        cache.put("b", response("b")).await?;
+        assert!(cache.get("a").await?.is_some());
+        cache.put("c", response("c")).await?;
+
+        assert!(cache.get("b").await?.is_none());
+        assert_eq!(cache.get("a").await?.unwrap().body, b"a");
+        assert_eq!(cache.get("c").await?.unwrap().body, b"c");
+        cache.remove("a").await?;
+        assert!(cache.get("a").await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn response_cache_should_skip_nondeterministic_requests() -> Result<()> {
+        let server = MockServer::start().await;
+        let cache = ResponseCache::memory(10);
+        let sdk = server.sdk().with_middleware(cache.clone());
+
+        for _ in 0..2 {
+            sdk.embedding(EmbeddingRequest::new("hi")).await?;
+            sdk.chat_completion(chat_request(Some(0.0))).await?;
+            sdk.chat_completion(chat_request(None)).await?;
+        }
+        assert_eq!(server.requests().len(), 4);
+
+        cache.set_mode(CacheMode::Refresh);
+        sdk.embedding(EmbeddingRequest::new("hi")).await?;
+        cache.set_mode(CacheMode::Enabled);
+        sdk.embedding(EmbeddingRequest::new("hi")).await?;
+        assert_eq!(server.requests().len(), 5);
+
+        cache.set_mode(CacheMode::Bypass);
+        sdk.embedding(EmbeddingRequest::new("hello")).await?;
+        cache.set_mode(CacheMode::Enabled);
+        sdk.embedding(EmbeddingRequest::new("hello")).await?;
+        assert_eq!(server.requests().len(), 7);
+        Ok(())
+    }
+
+    /// A backend failing every call, like a disk cache in a read-only directory.
+    struct BrokenCache;
+
+    #[async_trait]
+    impl CacheBackend for BrokenCache {
+        async fn get(&self, _: &str) -> Result<Option<CachedResponse>> {
+            Err(LlmError::Storage("read-only file system".into()))
+        }
+
+        async fn put(&self, _: &str, _: CachedResponse) -> Result<()> {
+            Err(LlmError::Storage("read-only file system".into()))
+        }
+
+        async fn remove(&self, _: &str) -> Result<()> {
+            Err(LlmError::Storage("read-only file system".into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn response_cache_should_not_fail_requests_on_storage_errors() -> Result<()> {
+        let server = MockServer::start().await;
+        let sdk = server
+            .sdk()
+            .with_middleware(ResponseCache::new(BrokenCache));
+        let res = sdk.embedding(EmbeddingRequest::new("hi")).await?;
+        assert_eq!(res.data.len(), 1);
+        sdk.embedding(EmbeddingRequest::new("hi")).await?;
+        assert_eq!(server.requests().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn response_cache_should_expire_and_persist() -> Result<()> {
+        let server = MockServer::start().await;
+        let sdk = server
+            .sdk()
+            .with_middleware(ResponseCache::memory(10).with_ttl(Duration::ZERO));
+        sdk.embedding(EmbeddingRequest::new("hi")).await?;
+        sdk.embedding(EmbeddingRequest::new("hi")).await?;
+        assert_eq!(server.requests().len(), 2);
+
+        let sdk = server
+            .sdk()
+            .with_middleware(ResponseCache::memory(10).with_ttl(Duration::MAX));
+        sdk.embedding(EmbeddingRequest::new("hi")).await?;
+        sdk.embedding(EmbeddingRequest::new("hi")).await?;
+        assert_eq!(server.requests().len(), 3);
+
+        let dir = temp_path("cache");
+        let speech = || SpeechRequest::new("The quick brown fox");
+        let audio = server
+            .sdk()
+            .with_middleware(ResponseCache::disk(&dir))
+            .speech(speech())
+            .await?;
+        let sdk = server.sdk().with_middleware(ResponseCache::disk(&dir));
+        assert_eq!(sdk.speech(speech()).await?, audio);
+        assert_eq!(server.requests().len(), 4);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&dir).ok();
+        Ok(())
+    }
+}
@@ -0,0 +1,445 @@
+use crate::{LlmError, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE},
+    Client, Request, Response,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+const REDACTED: &str = "[REDACTED]";
+/// Request headers holding credentials, never written to a cassette.
+const SECRET_HEADERS: [&str; 2] = ["authorization", "api-key"];
+/// Response headers describing the encoding of the body on the wire, which is recorded decoded.
+const TRANSPORT_HEADERS: [&str; 3] = ["content-encoding", "content-length", "transfer-encoding"];
+
+/// Records the requests sent by `LLmSdk` and their responses to a file, to replay them later without network.
+///
+/// Requests are matched by a hash of their method, path relative to the base url, and normalized body,
+/// so a cassette recorded against one server can be replayed with another base url or token.
+/// Identical requests are answered in the order they were recorded, and fail once their responses run out.
+/// Streamed responses are recorded whole.
+#[derive(Debug)]
+pub struct Cassette {
+    path: PathBuf,
+    mode: CassetteMode,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// Send the requests and save them with their responses, overwriting the file.
+    Record,
+    /// Answer the requests from the file, failing on requests it doesn't have.
+    Replay,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    interactions: Vec<Interaction>,
+    /// How many times each key was replayed.
+    played: HashMap<String, usize>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CassetteFile {
+    interactions: Vec<Interaction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Interaction {
+    key: String,
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedRequest {
+    method: String,
+    path: String,
+    headers: BTreeMap<String, String>,
+    body: RecordedBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    headers: BTreeMap<String, String>,
+    body: RecordedBody,
+}
+
+/// A body, kept readable in the file when it's json or text.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RecordedBody {
+    Empty,
+    Json(Value),
+    Text(String),
+    Base64(String),
+}
+
+impl Cassette {
+    /// Record to `path`, which is overwritten on the first request.
+    pub fn record(path: impl Into<PathBuf>) -> Self {
+        Self::new(path.into(), CassetteMode::Record, vec![])
+    }
+
+    /// Replay the interactions recorded in `path`.
+    pub fn replay(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let data = std::fs::read(&path)
+            .map_err(|e| cassette_error(&path, format!("failed to read: {}", e)))?;
+        let file: CassetteFile = serde_json::from_slice(&data)?;
+        Ok(Self::new(path, CassetteMode::Replay, file.interactions))
+    }
+
+    /// Replay `path` if it exists, record it otherwise.
+    pub fn auto(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        match path.exists() {
+            true => Self::replay(path),
+            false => Ok(Self::record(path)),
+        }
+    }
+
+    fn new(path: PathBuf, mode: CassetteMode, interactions: Vec<Interaction>) -> Self {
+        Self {
+            path,
+            mode,
+            state: Mutex::new(State {
+                interactions,
+                played: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Send `req` or replay its response. `base_url` is stripped from the url to match requests.
+    pub(crate) async fn send(
+        &self,
+        client: &Client,
+        base_url: &str,
+        req: Request,
+    ) -> Result<Response> {
+        let request = RecordedRequest::new(base_url, &req);
+        let key = request.key();
+        match self.mode {
+            CassetteMode::Replay => self.replay_response(&key, &request),
+            CassetteMode::Record => {
+                let res = client.execute(req).await?;
+                let response = RecordedResponse::read(res).await?;
+                let res = response.to_response();
+                self.save(Interaction {
+                    key,
+                    request,
+                    response,
+                })?;
+                res
+            }
+        }
+    }
+
+    fn replay_response(&self, key: &str, request: &RecordedRequest) -> Result<Response> {
+        let mut state = self.state.lock().unwrap();
+        let matching: Vec<_> = state
+            .interactions
+            .iter()
+            .filter(|i| i.key == key)
+            .cloned()
+            .collect();
+        let played = state.played.entry(key.to_string()).or_default();
+        let interaction = matching.get(*played).ok_or_else(|| {
+            cassette_error(
+                &self.path,
+                format!(
+                    "no recorded response for {} {}",
+                    request.method, request.path
+                ),
+            )
+        })?;
+        *played += 1;
+        interaction.response.to_response()
+    }
+
+    fn save(&self, interaction: Interaction) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.interactions.push(interaction);
+        let file = CassetteFile {
+            interactions: state.interactions.clone(),
+        };
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| cassette_error(&self.path, format!("failed to create dir: {}", e)))?;
+        }
+        std::fs::write(&self.path, serde_json::to_vec_pretty(&file)?)
+            .map_err(|e| cassette_error(&self.path, format!("failed to write: {}", e)))
+    }
+}
+
+impl RecordedRequest {
+    fn new(base_url: &str, req: &Request) -> Self {
+        let url = req.url().as_str();
+        let path = url
+            .strip_prefix(base_url.trim_end_matches('/'))
+            .unwrap_or(url);
+        let headers = req
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                let value = match SECRET_HEADERS.contains(&name.as_str()) {
+                    true => REDACTED.to_string(),
+                    false => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                };
+                (name.to_string(), value)
+            })
+            .collect();
+        let body = req.body().and_then(|b| b.as_bytes()).unwrap_or_default();
+        Self {
+            method: req.method().to_string(),
+            path: path.to_string(),
+            headers,
+            body: RecordedBody::new(req.headers(), body),
+        }
+    }
+
+    /// FNV-1a of the method, path and body, which is stable across runs and Rust versions.
+    /// Json bodies are hashed with sorted keys.
+    fn key(&self) -> String {
+        let body = match &self.body {
+            RecordedBody::Empty => String::new(),
+            RecordedBody::Json(value) => value.to_string(),
+            RecordedBody::Text(text) | RecordedBody::Base64(text) => text.clone(),
+        };
+        let data = format!("{} {}\n{}", self.method, self.path, body);
+        format!("{:016x}", fnv1a(data.as_bytes()))
+    }
+}
+
+/// The 64 bits FNV-1a hash of `data`.
+pub(crate) fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl RecordedResponse {
+    async fn read(res: Response) -> Result<Self> {
+        let status = res.status().as_u16();
+        let headers = res
+            .headers()
+            .iter()
+            .filter(|(name, _)| !TRANSPORT_HEADERS.contains(&name.as_str()))
+            .map(|(name, value)| {
+                let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                (name.to_string(), value)
+            })
+            .collect();
+        let content_type = res.headers().clone();
+        let body = res.bytes().await?;
+        Ok(Self {
+            status,
+            headers,
+            body: RecordedBody::new(&content_type, &body),
+        })
+    }
+
+    fn to_response(&self) -> Result<Response> {
+        let mut res = http::Response::builder().status(self.status);
+        for (name, value) in &self.headers {
+            match (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                (Ok(name), Ok(value)) => res = res.header(name, value),
+                _ => tracing::warn!("skipping invalid recorded header {}", name),
+            }
+        }
+        let res = res
+            .body(self.body.to_bytes()?)
+            .map_err(|e| LlmError::Cassette(format!("invalid recorded response: {}", e)))?;
+        Ok(res.into())
+    }
+}
+
+impl RecordedBody {
+    fn new(headers: &HeaderMap, body: &[u8]) -> Self {
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let text = std::str::from_utf8(body).ok();
+        match text {
+            _ if body.is_empty() => RecordedBody::Empty,
+            Some(text) if content_type.starts_with("application/json") => {
+                match serde_json::from_str(text) {
+                    Ok(value) => RecordedBody::Json(value),
+                    Err(_) => RecordedBody::Text(text.to_string()),
+                }
+            }
+            Some(text) if content_type.starts_with("text/") => RecordedBody::Text(text.to_string()),
+            _ => RecordedBody::Base64(BASE64_STANDARD.encode(body)),
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        match self {
+            RecordedBody::Empty => Ok(vec![]),
+            RecordedBody::Json(value) => Ok(serde_json::to_vec(value)?),
+            RecordedBody::Text(text) => Ok(text.clone().into_bytes()),
+            RecordedBody::Base64(data) => BASE64_STANDARD
+                .decode(data)
+                .map_err(|e| LlmError::Cassette(format!("invalid recorded body: {}", e))),
+        }
+    }
+}
+
+fn cassette_error(path: &Path, message: String) -> LlmError {
+    LlmError::Cassette(format!("{}: {}", path.display(), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{temp_path, MockResponse, MockServer},
+        ChatCompletionMessage, ChatCompletionRequestBuilder, EmbeddingRequest, Endpoint, LLmSdk,
+        SpeechRequest, WhisperRequest, WhisperRequestBuilder, WhisperResponseFormat,
+    };
+    use reqwest::StatusCode;
+
+    fn chat_request(content: &str) -> crate::ChatCompletionRequest {
+        ChatCompletionRequestBuilder::default()
+            .messages(vec![ChatCompletionMessage::new_user(content, "")])
+            .build()
+            .unwrap()
+    }
+
+    fn srt_request(file: Vec<u8>) -> WhisperRequest {
+        WhisperRequestBuilder::default()
+            .file(file)
+            .response_format(WhisperResponseFormat::Srt)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn recorded_request_key_should_ignore_base_url_token_and_key_order() -> Result<()> {
+        let client = Client::new();
+        let a = client
+            .post("http://localhost:1/v1/embeddings")
+            .bearer_auth("sk-a")
+            .header(CONTENT_TYPE, "application/json")
+            .body(r#"{"input":"hi","model":"m"}"#)
+            .build()?;
+        let b = client
+            .post("https://api.openai.com/v1/embeddings")
+            .bearer_auth("sk-b")
+            .header(CONTENT_TYPE, "application/json")
+            .body(r#"{"model":"m","input":"hi"}"#)
+            .build()?;
+        let a = RecordedRequest::new("http://localhost:1/v1", &a);
+        let b = RecordedRequest::new("https://api.openai.com/v1/", &b);
+        assert_eq!(a.path, "/embeddings");
+        assert_eq!(a.headers["authorization"], REDACTED);
+        assert_eq!(a.key(), b.key());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cassette_should_record_and_replay() -> Result<()> {
+        let path = temp_path("cassette").join("cassette.json");
+        let server = MockServer::start().await;
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::error(StatusCode::BAD_REQUEST, "Bad request"),
+        );
+        let sdk = LLmSdk::new(server.url(), "sk-secret")
+            .with_retry_policy(crate::RetryPolicy::none())
+            .with_cassette(Cassette::record(&path));
+        assert!(sdk.chat_completion(chat_request("Hello!")).await.is_err());
+        sdk.chat_completion(chat_request("Hello!")).await?;
+        sdk.embedding(EmbeddingRequest::new("hi")).await?;
+        let audio = sdk.speech(SpeechRequest::new("hi")).await?;
+        let text = sdk
+            .whisper(WhisperRequest::transcription(vec![0xff, 0xfb, 0x90]))
+            .await?
+            .text;
+        server.enqueue(Endpoint::Whisper, MockResponse::text("first"));
+        server.enqueue(Endpoint::Whisper, MockResponse::text("second"));
+        sdk.whisper(srt_request(vec![0xff, 0xfb, 0x90])).await?;
+        sdk.whisper(srt_request(vec![0xff, 0xfb, 0x91])).await?;
+        drop(server);
+
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert!(!data.contains("sk-secret"));
+        assert!(data.contains("base64"));
+
+        let sdk = LLmSdk::new("http://localhost:1/v1", "").with_cassette(Cassette::replay(&path)?);
+        let err = sdk
+            .chat_completion(chat_request("Hello!"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status(), Some(StatusCode::BAD_REQUEST));
+        let res = sdk.chat_completion(chat_request("Hello!")).await?;
+        assert_eq!(
+            res.choices[0].message.content(),
+            Some("Mock reply to: Hello!")
+        );
+        assert_eq!(
+            sdk.embedding(EmbeddingRequest::new("hi")).await?.data.len(),
+            1
+        );
+        assert_eq!(sdk.speech(SpeechRequest::new("hi")).await?, audio);
+        let res = sdk
+            .whisper(WhisperRequest::transcription(vec![0xff, 0xfb, 0x90]))
+            .await?;
+        assert_eq!(res.text, text);
+        let res = sdk.whisper(srt_request(vec![0xff, 0xfb, 0x91])).await?;
+        assert_eq!(res.text, "second");
+        let res = sdk.whisper(srt_request(vec![0xff, 0xfb, 0x90])).await?;
+        assert_eq!(res.text, "first");
+
+        let err = sdk
+            .chat_completion(chat_request("Goodbye!"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, LlmError::Cassette(_)), "{}", err);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).ok();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cassette_should_fail_once_recorded_responses_run_out() -> Result<()> {
+        let path = temp_path("cassette").join("cassette.json");
+        let server = MockServer::start().await;
+        let sdk = server.sdk().with_cassette(Cassette::record(&path));
+        sdk.chat_completion(chat_request("Hello!")).await?;
+        sdk.chat_completion(chat_request("Hello!")).await?;
+        drop(server);
+
+        let sdk = LLmSdk::new("http://localhost:1/v1", "").with_cassette(Cassette::replay(&path)?);
+        sdk.chat_completion(chat_request("Hello!")).await?;
+        sdk.chat_completion(chat_request("Hello!")).await?;
+        let err = sdk
+            .chat_completion(chat_request("Hello!"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, LlmError::Cassette(_)), "{}", err);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).ok();
+        Ok(())
+    }
+}
@@ -0,0 +1,362 @@
+use crate::{
+    AssistantMessage, ChatCompletionMessage, ChatCompletionRequest, ChatCompletionRequestBuilder,
+    ChatCompletionResponse, ChatCompletionUsage, ConversationStore, LLmSdk, Result, ToolRegistry,
+    UserContent,
+};
+use std::ops::Range;
+
+/// A chat session: the history of the conversation and the settings of the requests sent with it.
+/// Every reply is appended to the history, so the next message is sent with the whole conversation.
+#[derive(Debug, Clone)]
+pub struct Conversation {
+    request: ChatCompletionRequest,
+    /// Usage of every turn, a turn being a user message and everything that answers it.
+    usage: Vec<ChatCompletionUsage>,
+    /// Usage of the turns folded into a summary.
+    folded_usage: ChatCompletionUsage,
+}
+
+impl Conversation {
+    /// A conversation with the default request settings.
+    pub fn new(system_prompt: impl Into<String>) -> Self {
+        let request = ChatCompletionRequestBuilder::default()
+            .messages(vec![ChatCompletionMessage::new_system(system_prompt, "")])
+            .build()
+            .unwrap();
+        Self::from_request(request)
+    }
+
+    /// A conversation sending requests like `request`: same model, sampling parameters, tools...
+    /// The messages of `request` are the start of the history.
+    pub fn from_request(request: ChatCompletionRequest) -> Self {
+        Self {
+            request,
+            usage: vec![],
+            folded_usage: ChatCompletionUsage::default(),
+        }
+    }
+
+    /// The content of the first system message.
+    pub fn system_prompt(&self) -> Option<&str> {
+        self.messages().iter().find_map(|m| match m {
+            ChatCompletionMessage::System(m) => Some(m.content()),
+            _ => None,
+        })
+    }
+
+    /// The history of the conversation.
+    pub fn messages(&self) -> &[ChatCompletionMessage] {
+        self.request.messages()
+    }
+
+    /// The request sent for the next turn, without the next user message.
+    pub fn request(&self) -> &ChatCompletionRequest {
+        &self.request
+    }
+
+    /// The number of turns so far, that is the number of user messages.
+    pub fn turns(&self) -> usize {
+        self.messages()
+            .iter()
+            .filter(|m| matches!(m, ChatCompletionMessage::User(_)))
+            .count()
+    }
+
+    /// The usage of all the requests sent in this conversation.
+    pub fn usage(&self) -> ChatCompletionUsage {
+        let mut total = self.folded_usage;
+        for usage in &self.usage {
+            total += *usage;
+        }
+        total
+    }
+
+    /// The last reply of the model.
+    pub fn last_reply(&self) -> Option<&AssistantMessage> {
+        self.messages().iter().rev().find_map(|m| match m {
+            ChatCompletionMessage::Assistant(m) => Some(m),
+            _ => None,
+        })
+    }
+
+    /// Append a message to the history without sending it.
+    pub fn push(&mut self, message: ChatCompletionMessage) {
+        self.request.push_message(message);
+    }
+
+    /// Send a user message and return the reply, both appended to the history.
+    /// The history is left untouched if the request fails.
+    pub async fn send(
+        &mut self,
+        sdk: &LLmSdk,
+        content: impl Into<UserContent>,
+    ) -> Result<AssistantMessage> {
+        let len = self.start_turn(content);
+        let res = sdk.chat_completion(self.request.clone()).await;
+        self.end_turn(len, res)
+    }
+
+    /// Send a user message and run the tool calls of the model until it replies, see `LLmSdk::run_with_tools`.
+    /// The tool calls, their results and the reply are appended to the history.
+    /// The tools are only offered for this turn, and the history is left untouched if a request fails.
+    pub async fn send_with_tools(
+        &mut self,
+        sdk: &LLmSdk,
+        content: impl Into<UserContent>,
+        tools: &ToolRegistry,
+        max_iterations: usize,
+    ) -> Result<AssistantMessage> {
+        let len = self.start_turn(content);
+        let mut req = self.request.clone();
+        let res = sdk.run_with_tools(&mut req, tools, max_iterations).await;
+        *self.request.messages_mut() = std::mem::take(req.messages_mut());
+        self.end_turn(len, res)
+    }
+
+    /// Save the history in `store` as the conversation `id`. The usage is not saved.
+    pub async fn save(&self, store: &dyn ConversationStore, id: &str) -> Result<()> {
+        store.save(id, self.messages()).await
+    }
+
+    /// Resume the conversation `id` saved in `store`, sending requests like `request`.
+    /// The saved history replaces the messages of `request`. `None` if the conversation was never saved.
+    pub async fn load(
+        store: &dyn ConversationStore,
+        id: &str,
+        mut request: ChatCompletionRequest,
+    ) -> Result<Option<Self>> {
+        let Some(messages) = store.load(id).await? else {
+            return Ok(None);
+        };
+        *request.messages_mut() = messages;
+        Ok(Some(Self::from_request(request)))
+    }
+
+    /// A copy of the conversation as it was after `turns` turns, to explore another branch from there.
+    /// `fork(0)` keeps the messages sent before the first user message, like the system prompt.
+    pub fn fork(&self, turns: usize) -> Self {
+        let mut fork = self.clone();
+        let end = self
+            .messages()
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| matches!(m, ChatCompletionMessage::User(_)))
+            .nth(turns)
+            .map(|(i, _)| i);
+        if let Some(end) = end {
+            fork.request.messages_mut().truncate(end);
+        }
+        fork.usage.truncate(turns);
+        fork
+    }
+
+    /// Replace the messages in `range` with `summary`. The usage of the folded turns still counts in `usage`.
+    /// `previous` is the index of the summary `summary` replaces, before `range`: `summary` takes its place.
+    pub(crate) fn fold(
+        &mut self,
+        previous: Option<usize>,
+        range: Range<usize>,
+        summary: ChatCompletionMessage,
+    ) {
+        let messages = self.request.messages_mut();
+        let start = range.start;
+        let folded: Vec<_> = messages.splice(range, [summary]).collect();
+        if let Some(previous) = previous {
+            messages.swap(previous, start);
+            messages.remove(start);
+        }
+        let turns = folded
+            .iter()
+            .filter(|m| matches!(m, ChatCompletionMessage::User(_)))
+            .count();
+        // the folded turns are the first ones, a summary never sits after a turn
+        for usage in self.usage.drain(..turns.min(self.usage.len())) {
+            self.folded_usage += usage;
+        }
+    }
+
+    /// Push the user message, returning the length of the history before it.
+    fn start_turn(&mut self, content: impl Into<UserContent>) -> usize {
+        let len = self.messages().len();
+        self.push(ChatCompletionMessage::new_user(content, ""));
+        len
+    }
+
+    /// Record the reply, or roll the history back to `len` messages if the turn failed.
+    fn end_turn(
+        &mut self,
+        len: usize,
+        res: Result<ChatCompletionResponse>,
+    ) -> Result<AssistantMessage> {
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                self.request.messages_mut().truncate(len);
+                return Err(e);
+            }
+        };
+        // turns pushed by hand have no usage
+        self.usage
+            .resize(self.turns() - 1, ChatCompletionUsage::default());
+        self.usage.push(res.usage);
+
+        let message = res
+            .choices
+            .into_iter()
+            .next()
+            .map(|c| c.message)
+            .unwrap_or_default();
+        self.push(ChatCompletionMessage::Assistant(message.clone()));
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{MockResponse, MockServer},
+        ChatCompletionChoice, Endpoint, LlmError,
+    };
+
+    fn reply(content: &str, tokens: usize) -> Result<ChatCompletionResponse> {
+        Ok(ChatCompletionResponse {
+            choices: vec![ChatCompletionChoice {
+                message: serde_json::from_value(serde_json::json!({ "content": content }))?,
+                ..Default::default()
+            }],
+            usage: usage(tokens),
+            ..Default::default()
+        })
+    }
+
+    fn usage(tokens: usize) -> ChatCompletionUsage {
+        ChatCompletionUsage {
+            completion_tokens: tokens,
+            prompt_tokens: tokens,
+            total_tokens: tokens * 2,
+        }
+    }
+
+    fn conversation() -> Conversation {
+        let mut conversation = Conversation::new("You are a helpful assistant.");
+        for (question, answer) in [
+            ("Hi!", "Hello!"),
+            ("How are you?", "Fine."),
+            ("Bye!", "Bye."),
+        ] {
+            let len = conversation.start_turn(question);
+            conversation.end_turn(len, reply(answer, 10)).unwrap();
+        }
+        conversation
+    }
+
+    #[test]
+    fn conversation_should_track_history_and_usage() {
+        let conversation = conversation();
+        assert_eq!(
+            conversation.system_prompt(),
+            Some("You are a helpful assistant.")
+        );
+        assert_eq!(conversation.messages().len(), 7);
+        assert_eq!(conversation.turns(), 3);
+        assert_eq!(conversation.usage(), usage(30));
+        assert_eq!(conversation.last_reply().unwrap().content(), Some("Bye."));
+    }
+
+    #[test]
+    fn conversation_should_roll_back_failed_turns() {
+        let mut conversation = conversation();
+        let len = conversation.start_turn("Are you there?");
+        let err = LlmError::Validation("offline".into());
+        assert!(conversation.end_turn(len, Err(err)).is_err());
+        assert_eq!(conversation.messages().len(), 7);
+        assert_eq!(conversation.turns(), 3);
+    }
+
+    #[test]
+    fn conversation_fork_should_keep_earlier_turns() {
+        let conversation = conversation();
+
+        let fork = conversation.fork(1);
+        assert_eq!(fork.messages().len(), 3);
+        assert_eq!(fork.turns(), 1);
+        assert_eq!(fork.usage(), usage(10));
+        assert_eq!(fork.last_reply().unwrap().content(), Some("Hello!"));
+
+        let fork = conversation.fork(0);
+        assert_eq!(fork.messages().len(), 1);
+        assert_eq!(fork.usage(), usage(0));
+
+        let fork = conversation.fork(5);
+        assert_eq!(fork.messages().len(), 7);
+        assert_eq!(fork.usage(), usage(30));
+    }
+
+    #[tokio::test]
+    async fn conversation_send_should_work() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        server.enqueue(Endpoint::ChatCompletions, MockResponse::chat("Hello"));
+        server.enqueue(Endpoint::ChatCompletions, MockResponse::chat("Tyr"));
+        let sdk = &server.sdk();
+        let mut conversation = Conversation::new("You answer with a single word.");
+        conversation.send(sdk, "My name is Tyr. Say hello.").await?;
+        let reply = conversation.send(sdk, "What is my name?").await?;
+
+        assert_eq!(reply.content(), Some("Tyr"));
+        assert_eq!(conversation.messages().len(), 5);
+        assert_eq!(conversation.usage().total_tokens, 30);
+
+        // the second request carries the whole history
+        let body = server.requests()[1].json().unwrap();
+        let contents: Vec<_> = body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["content"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            contents,
+            [
+                "You answer with a single word.",
+                "My name is Tyr. Say hello.",
+                "Hello",
+                "What is my name?"
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn conversation_send_with_tools_should_keep_tools_to_the_turn() -> anyhow::Result<()> {
+        #[derive(serde::Deserialize, schemars::JsonSchema)]
+        struct Args {
+            city: String,
+        }
+
+        let server = MockServer::start().await;
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::tool_call("get_weather", serde_json::json!({ "city": "Paris" })),
+        );
+        server.enqueue(Endpoint::ChatCompletions, MockResponse::chat("Sunny."));
+        let mut tools = ToolRegistry::new();
+        tools.register("get_weather", "Get the weather.", |args: Args| async move {
+            Ok::<_, LlmError>(format!("sunny in {}", args.city))
+        });
+        let sdk = &server.sdk();
+        let mut conversation = Conversation::new("You are a helpful assistant.");
+        let reply = conversation
+            .send_with_tools(sdk, "Weather in Paris?", &tools, 3)
+            .await?;
+        assert_eq!(reply.content(), Some("Sunny."));
+        assert_eq!(conversation.messages().len(), 5);
+        assert!(conversation.request().tools().is_empty());
+
+        conversation.send(sdk, "Thanks!").await?;
+        let requests = server.requests();
+        assert!(requests[1].json().unwrap()["tools"].is_array());
+        assert!(requests[2].json().unwrap().get("tools").is_none());
+        Ok(())
+    }
+}
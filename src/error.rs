@@ -0,0 +1,228 @@
+use reqwest::{header::HeaderMap, Response, StatusCode};
+use serde::{Deserialize, Deserializer};
+use std::{fmt, time::Duration};
+use thiserror::Error;
+
+pub type Result<T, E = LlmError> = std::result::Result<T, E>;
+
+/// Errors returned by `LLmSdk`.
+#[derive(Debug, Error)]
+pub enum LlmError {
+    /// The API answered with a non-2xx status.
+    #[error("API failed: {0}")]
+    Api(Box<ApiError>),
+    /// The request could not be sent or the response body could not be read.
+    #[error("transport error: {0}")]
+    Transport(#[from] reqwest::Error),
+    /// The response body is not what we expected.
+    #[error("failed to decode response: {0}")]
+    Decode(#[from] serde_json::Error),
+    /// The reply doesn't match the json schema it was asked to match, or the request doesn't fit its limits.
+    #[error("validation failed: {0}")]
+    Validation(String),
+    /// `LLmSdkBuilder` was given an invalid setting.
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    /// A conversation store failed to save or load.
+    #[error("storage error: {0}")]
+    Storage(String),
+    /// A prompt template failed to parse, or to render with the given variables.
+    #[error("template error: {0}")]
+    Template(String),
+    /// A cassette failed to load or save, or has no recorded response for a request.
+    #[error("cassette error: {0}")]
+    Cassette(String),
+    /// `LLmSdk::run_with_tools` gave up because the model kept calling tools.
+    #[error("model still calling tools after {0} iterations")]
+    ToolIterations(usize),
+}
+
+/// A non-2xx response, or an error event in a stream, with the error object OpenAI puts in the body.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    /// HTTP status of the response. It's the status of the stream for errors sent in a stream.
+    pub status: StatusCode,
+    /// The `x-request-id` header, to quote when contacting support.
+    pub request_id: Option<String>,
+    /// The error message. Falls back to the raw body if it's not an OpenAI error object.
+    pub message: String,
+    /// The error type, e.g. `invalid_request_error`.
+    pub r#type: Option<String>,
+    /// The error code, e.g. `context_length_exceeded`.
+    pub code: Option<String>,
+    /// The request parameter that caused the error.
+    pub param: Option<String>,
+    /// How long the server asked us to wait before retrying.
+    pub retry_after: Option<Duration>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: ErrorObject,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorObject {
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    r#type: Option<String>,
+    #[serde(default, deserialize_with = "string_or_number")]
+    code: Option<String>,
+    #[serde(default)]
+    param: Option<String>,
+}
+
+impl LlmError {
+    /// Build an error from a non-2xx response.
+    pub(crate) async fn from_response(res: Response) -> Self {
+        let status = res.status();
+        let headers = res.headers().clone();
+        let body = match res.text().await {
+            Ok(body) => body,
+            Err(e) => return e.into(),
+        };
+        ApiError::new(status, &headers, &body).into()
+    }
+
+    /// HTTP status of the response, if the API answered.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            LlmError::Api(e) => Some(e.status),
+            LlmError::Transport(e) => e.status(),
+            _ => None,
+        }
+    }
+
+    /// The API error, if the API answered with a non-2xx status.
+    pub fn api_error(&self) -> Option<&ApiError> {
+        match self {
+            LlmError::Api(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    pub fn is_rate_limited(&self) -> bool {
+        self.status() == Some(StatusCode::TOO_MANY_REQUESTS)
+    }
+
+    pub fn is_unauthorized(&self) -> bool {
+        self.status() == Some(StatusCode::UNAUTHORIZED)
+    }
+
+    pub fn is_context_length_exceeded(&self) -> bool {
+        self.api_error()
+            .is_some_and(|e| e.code.as_deref() == Some("context_length_exceeded"))
+    }
+}
+
+impl ApiError {
+    pub(crate) fn new(status: StatusCode, headers: &HeaderMap, body: &str) -> Self {
+        let request_id = headers
+            .get("x-request-id")
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let retry_after = crate::retry::retry_after(headers);
+        match serde_json::from_str::<ErrorResponse>(body) {
+            Ok(ErrorResponse { error }) => ApiError {
+                status,
+                request_id,
+                message: error.message,
+                r#type: error.r#type,
+                code: error.code,
+                param: error.param,
+                retry_after,
+            },
+            Err(_) => ApiError {
+                status,
+                request_id,
+                message: body.to_string(),
+                r#type: None,
+                code: None,
+                param: None,
+                retry_after,
+            },
+        }
+    }
+}
+
+impl ApiError {
+    /// The error sent as the event `data` of a stream, if it is an error object.
+    pub(crate) fn from_event(status: StatusCode, headers: &HeaderMap, data: &str) -> Option<Self> {
+        serde_json::from_str::<ErrorResponse>(data)
+            .is_ok()
+            .then(|| Self::new(status, headers, data))
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.status)?;
+        if let Some(code) = self.code.as_ref().or(self.r#type.as_ref()) {
+            write!(f, " ({})", code)?;
+        }
+        write!(f, ": {}", self.message)?;
+        if let Some(id) = &self.request_id {
+            write!(f, " [request id: {}]", id)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<ApiError> for LlmError {
+    fn from(value: ApiError) -> Self {
+        LlmError::Api(Box::new(value))
+    }
+}
+
+/// Some OpenAI compatible servers send the error code as a number.
+fn string_or_number<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    Ok(
+        match Option::<serde_json::Value>::deserialize(deserializer)? {
+            Some(serde_json::Value::String(s)) => Some(s),
+            Some(serde_json::Value::Number(n)) => Some(n.to_string()),
+            _ => None,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_error_should_parse_openai_error_body() {
+        let body = r#"{"error":{"message":"This model's maximum context length is 4097 tokens.","type":"invalid_request_error","param":"messages","code":"context_length_exceeded"}}"#;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", "req_1".parse().unwrap());
+        let err: LlmError = ApiError::new(StatusCode::BAD_REQUEST, &headers, body).into();
+
+        assert_eq!(err.status(), Some(StatusCode::BAD_REQUEST));
+        assert!(err.is_context_length_exceeded());
+        assert!(!err.is_rate_limited());
+        let api = err.api_error().unwrap();
+        assert_eq!(api.r#type.as_deref(), Some("invalid_request_error"));
+        assert_eq!(api.param.as_deref(), Some("messages"));
+        assert_eq!(
+            err.to_string(),
+            "API failed: 400 Bad Request (context_length_exceeded): This model's maximum context length is 4097 tokens. [request id: req_1]"
+        );
+    }
+
+    #[test]
+    fn api_error_should_keep_unparsed_body() {
+        let err = ApiError::new(
+            StatusCode::BAD_GATEWAY,
+            &HeaderMap::new(),
+            "upstream timed out",
+        );
+        assert_eq!(err.message, "upstream timed out");
+        assert_eq!(err.code, None);
+
+        let body = r#"{"error":{"message":"Rate limit reached","type":"requests","code":429}}"#;
+        let err = ApiError::new(StatusCode::TOO_MANY_REQUESTS, &HeaderMap::new(), body);
+        assert_eq!(err.code.as_deref(), Some("429"));
+    }
+}
@@ -1,24 +1,93 @@
+extern crate self as q_bot;
+
 mod api;
+mod builder;
+mod cache;
+mod cassette;
+mod conversation;
+mod error;
+mod memory;
+mod middleware;
+mod model;
+mod prompt;
+mod rag;
+mod retry;
+mod splitter;
+mod sse;
+mod store;
+mod structured;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod tokens;
+mod tool;
+mod vector;
 
-use anyhow::{Ok, Result};
-use api::*;
+pub use api::*;
 use async_trait::async_trait;
+pub use builder::*;
 use bytes::Bytes;
-use reqwest::{Client, RequestBuilder, Response};
+pub use cache::*;
+pub use cassette::*;
+pub use conversation::*;
+pub use error::*;
+use futures::{future, Stream, StreamExt, TryStreamExt};
+pub use memory::*;
+pub use middleware::*;
+pub use model::{Modality, ModelInfo, ModelInfoBuilder, ModelPricing, ModelRegistry};
+pub use prompt::*;
+pub use q_bot_macros::tool;
+pub use rag::*;
+use reqwest::{
+    header::{HeaderMap, AUTHORIZATION},
+    Client, RequestBuilder, Response, StatusCode,
+};
+pub use retry::*;
 use schemars::{schema_for, JsonSchema};
-use std::time::Duration;
+use serde::de::DeserializeOwned;
+pub use splitter::*;
+use std::{collections::HashMap, pin::Pin, sync::Arc, time::Duration};
+pub use store::*;
+use structured::StructuredOutput;
+pub use tokens::*;
+pub use tool::*;
+pub use vector::*;
 
-static TIMEOUT: u64 = 30;
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 pub struct LLmSdk {
     pub(crate) base_url: String,
     pub(crate) token: String,
     pub(crate) client: Client,
+    pub(crate) retry: RetryPolicy,
+    pub(crate) headers: HeaderMap,
+    pub(crate) timeout: Duration,
+    pub(crate) endpoint_timeouts: HashMap<Endpoint, Duration>,
+    pub(crate) models: ModelRegistry,
+    pub(crate) cassette: Option<Arc<Cassette>>,
+    pub(crate) middlewares: MiddlewareChain,
+}
+
+/// Stream of chunks returned by `LLmSdk::chat_completion_stream`.
+pub type ChatCompletionStream = Pin<Box<dyn Stream<Item = Result<ChatCompletionChunk>> + Send>>;
+
+/// The API endpoints, to configure them separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    ChatCompletions,
+    Embeddings,
+    Images,
+    Speech,
+    /// Both transcriptions and translations.
+    Whisper,
 }
 
 pub trait IntoRequest {
-    fn into_request(self, client: Client) -> RequestBuilder;
+    /// The endpoint this request is sent to.
+    fn endpoint(&self) -> Endpoint;
+    /// Build the request against `base_url`, which is the base url of the `LLmSdk` that sends it.
+    fn into_request(self, base_url: &str, client: Client) -> RequestBuilder;
 }
 
 impl LLmSdk {
@@ -27,38 +96,170 @@ impl LLmSdk {
             base_url: base_url.into(),
             token: token.into(),
             client: Client::new(),
+            retry: RetryPolicy::default(),
+            headers: HeaderMap::new(),
+            timeout: DEFAULT_TIMEOUT,
+            endpoint_timeouts: HashMap::new(),
+            models: ModelRegistry::default(),
+            cassette: None,
+            middlewares: MiddlewareChain::default(),
         }
     }
+
+    pub fn builder() -> LLmSdkBuilder {
+        LLmSdkBuilder::default()
+    }
+
+    /// Replace the default retry policy. Use `RetryPolicy::none()` to disable retries.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Record the requests to `cassette`, or replay them from it.
+    pub fn with_cassette(mut self, cassette: Cassette) -> Self {
+        self.cassette = Some(Arc::new(cassette));
+        self
+    }
+
+    /// Add `middleware` to the end of the chain every request goes through.
+    pub fn with_middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// Metadata of the known models: context window, pricing, supported inputs...
+    pub fn models(&self) -> &ModelRegistry {
+        &self.models
+    }
+
+    /// Drop messages from `req` with `strategy` until it fits in the context window of its model,
+    /// leaving room for `max_tokens` tokens of completion, or a default budget without `max_tokens`.
+    /// The model must be known to `models()`.
+    pub fn fit_to_context(
+        &self,
+        req: &mut ChatCompletionRequest,
+        strategy: Truncation,
+    ) -> Result<()> {
+        let info = self.models.get(req.model()).ok_or_else(|| {
+            LlmError::Config(format!(
+                "unknown model {}, register it with LLmSdkBuilder::model",
+                req.model()
+            ))
+        })?;
+        let budget = info.prompt_budget(req.max_tokens());
+        TokenCounter::for_model(req.model()).truncate(req, budget, strategy)
+    }
+
     pub async fn chat_completion(
         &self,
         req: ChatCompletionRequest,
     ) -> Result<ChatCompletionResponse> {
-        let req = self.prepare_request(req);
-        let res = req.send_and_log().await?;
+        let res = self.send(req).await?;
 
-        Ok(res.json::<ChatCompletionResponse>().await?)
+        res.decode::<ChatCompletionResponse>().await
+    }
+
+    /// Send the request with `stream` enabled and yield the partial deltas as they arrive.
+    /// Use `ChatCompletionResponse::from_stream` to fold them back into a full response.
+    pub async fn chat_completion_stream(
+        &self,
+        req: ChatCompletionRequest,
+    ) -> Result<ChatCompletionStream> {
+        let res = self.send(req.with_stream(true)).await?;
+
+        let status = res.status();
+        let headers = res.headers().clone();
+        let stream = sse::events(res.bytes_stream())
+            .try_take_while(|data| future::ok(data != "[DONE]"))
+            .and_then(move |data| future::ready(decode_chunk(status, &headers, &data)));
+        Ok(stream.boxed())
+    }
+
+    /// Keep sending `req` and running the tool calls of the model with `tools`, until the model answers
+    /// without calling tools. The tool calls and their results are pushed into `req`, and the final response is returned,
+    /// with the usage of all the requests sent.
+    pub async fn run_with_tools(
+        &self,
+        req: &mut ChatCompletionRequest,
+        tools: &ToolRegistry,
+        max_iterations: usize,
+    ) -> Result<ChatCompletionResponse> {
+        req.add_tools(tools.tools());
+        let mut usage = ChatCompletionUsage::default();
+        for _ in 0..max_iterations {
+            let mut res = self.chat_completion(req.clone()).await?;
+            usage += res.usage;
+            res.usage = usage;
+            let Some(choice) = res.choices.first() else {
+                return Ok(res);
+            };
+            if choice.message.tool_calls().is_empty() {
+                return Ok(res);
+            }
+
+            req.push_message(ChatCompletionMessage::Assistant(choice.message.clone()));
+            let calls = choice.message.tool_calls().iter().map(|c| tools.call(c));
+            for message in future::join_all(calls).await {
+                req.push_message(message);
+            }
+        }
+        Err(LlmError::ToolIterations(max_iterations))
+    }
+
+    /// Ask for a reply matching the json schema of `T`, validate it and deserialize it. When the reply doesn't
+    /// parse or doesn't match the schema, the model is told what's wrong and asked again, up to `reprompts` times.
+    pub async fn chat_completion_typed<T: JsonSchema + DeserializeOwned>(
+        &self,
+        req: ChatCompletionRequest,
+        reprompts: usize,
+    ) -> Result<T> {
+        let output = StructuredOutput::new(&T::to_schema())?;
+        let mut req = req.with_response_format(ChatResponseFormatObject::json_schema::<T>());
+        let mut attempt = 0;
+        loop {
+            let res = self.chat_completion(req.clone()).await?;
+            let message = res
+                .choices
+                .into_iter()
+                .next()
+                .map(|c| c.message)
+                .unwrap_or_default();
+            match output.parse(&message) {
+                Err(e) if attempt < reprompts && message.refusal().is_none() => {
+                    tracing::warn!("reply doesn't match the schema, asking again: {}", e);
+                    req.push_message(ChatCompletionMessage::Assistant(message));
+                    req.push_message(ChatCompletionMessage::new_user(
+                        format!(
+                            "Your reply is invalid: {}. Reply again with JSON matching the schema.",
+                            e
+                        ),
+                        "",
+                    ));
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
     }
 
     pub async fn create_image(&self, req: CreateImageRequest) -> Result<CreateImageResponse> {
-        let req = self.prepare_request(req);
-        let res = req.send_and_log().await?;
-        Ok(res.json::<CreateImageResponse>().await?)
+        let res = self.send(req).await?;
+        res.decode::<CreateImageResponse>().await
     }
 
     /// Response media stream
     pub async fn speech(&self, req: SpeechRequest) -> Result<Bytes> {
-        let req = self.prepare_request(req);
-        let res = req.send_and_log().await?;
+        let res = self.send(req).await?;
         Ok(res.bytes().await?)
     }
 
     pub async fn whisper(&self, req: WhisperRequest) -> Result<WhisperResponse> {
         let is_json = req.response_format == WhisperResponseFormat::Json;
-        let req = self.prepare_request(req);
-        let res = req.send_and_log().await?;
+        let res = self.send(req).await?;
 
         let ret = if is_json {
-            res.json::<WhisperResponse>().await?
+            res.decode::<WhisperResponse>().await?
         } else {
             WhisperResponse {
                 text: res.text().await?,
@@ -69,42 +270,159 @@ impl LLmSdk {
     }
 
     pub async fn embedding(&self, req: EmbeddingRequest) -> Result<EmbeddingResponse> {
-        let req = self.prepare_request(req);
-        let res = req.send_and_log().await?;
-        Ok(res.json::<EmbeddingResponse>().await?)
+        req.validate()?;
+        let res = self.send(req).await?;
+        res.decode::<EmbeddingResponse>().await
+    }
+
+    /// Embed any number of inputs, split into requests fitting `batching` and sent concurrently.
+    /// Every request is retried according to the retry policy.
+    /// The embeddings are returned in the order of the inputs, with the usage of all the requests.
+    pub async fn embed_many(
+        &self,
+        req: EmbeddingRequest,
+        batching: &EmbeddingBatching,
+    ) -> Result<EmbeddingResponse> {
+        let model = req.model().to_string();
+        let batches = req
+            .split(batching)
+            .into_iter()
+            .map(|(start, req)| async move {
+                let expected = req.input().len();
+                let mut res = self.embedding(req).await?;
+                if res.data.len() != expected {
+                    return Err(LlmError::Validation(format!(
+                        "expected {} embeddings, got {}",
+                        expected,
+                        res.data.len()
+                    )));
+                }
+                res.data.iter_mut().for_each(|d| d.shift_index(start));
+                Ok(res)
+            });
+        let responses: Vec<_> = futures::stream::iter(batches)
+            .buffer_unordered(batching.concurrency.max(1))
+            .try_collect()
+            .await?;
+
+        let mut ret = EmbeddingResponse {
+            object: "list".into(),
+            data: vec![],
+            model,
+            usage: EmbeddingUsage::default(),
+        };
+        for res in responses {
+            ret.model = res.model;
+            ret.data.extend(res.data);
+            ret.usage += res.usage;
+        }
+        ret.data.sort_by_key(|d| d.index());
+        Ok(ret)
+    }
+
+    /// Send the request, retrying transient failures according to the retry policy.
+    /// The request is rebuilt for every attempt, so multipart bodies are retried as well as json ones.
+    async fn send(&self, req: impl IntoRequest + Clone) -> Result<Response> {
+        let mut attempt = 1;
+        loop {
+            match self.execute(req.clone()).await {
+                Err(e) if attempt < self.retry.max_attempts && self.retry.is_retryable(&e) => {
+                    let delay = self.retry.delay(attempt, &e);
+                    tracing::warn!(
+                        "retrying in {:?} ({}/{}): {}",
+                        delay,
+                        attempt,
+                        self.retry.max_attempts,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Send the request once through the middlewares, and the cassette if there is one.
+    async fn execute(&self, req: impl IntoRequest) -> Result<Response> {
+        let endpoint = req.endpoint();
+        let req = self.prepare_request(req).build()?;
+        let next = Next::new(
+            &self.middlewares,
+            &self.client,
+            &self.base_url,
+            self.cassette.as_deref(),
+        );
+        let res = next.run(endpoint, req).await?;
+        log_errors(res).await
     }
 
     fn prepare_request(&self, req: impl IntoRequest) -> RequestBuilder {
-        let req = req.into_request(self.client.clone());
-        let req = if self.token.is_empty() {
+        let timeout = self
+            .endpoint_timeouts
+            .get(&req.endpoint())
+            .copied()
+            .unwrap_or(self.timeout);
+        let req = req
+            .into_request(&self.base_url, self.client.clone())
+            .headers(self.headers.clone());
+        // a custom authorization header wins over the token
+        let req = if self.token.is_empty() || self.headers.contains_key(AUTHORIZATION) {
             req
         } else {
             req.bearer_auth(&self.token)
         };
-        req.timeout(Duration::from_secs(TIMEOUT))
+        req.timeout(timeout)
     }
 }
 
-#[async_trait]
-trait SendAndLong {
-    async fn send_and_log(self) -> Result<Response>;
+/// Turn non-2xx responses into errors, and log them.
+async fn log_errors(res: Response) -> Result<Response> {
+    let status = res.status();
+    if status.is_client_error() || status.is_server_error() {
+        let err = LlmError::from_response(res).await;
+        tracing::error!("{}", err);
+        return Err(err);
+    }
+
+    Ok(res)
+}
+
+/// Decode an event of a chat completion stream. Error events are turned into API errors, and logged.
+fn decode_chunk(
+    status: StatusCode,
+    headers: &HeaderMap,
+    data: &str,
+) -> Result<ChatCompletionChunk> {
+    if let Some(err) = ApiError::from_event(status, headers, data) {
+        let err = LlmError::from(err);
+        tracing::error!("{}", err);
+        return Err(err);
+    }
+    Ok(serde_json::from_str(data)?)
 }
 
 #[async_trait]
-impl SendAndLong for RequestBuilder {
-    async fn send_and_log(self) -> Result<Response> {
-        let res = self.send().await?;
-        let status = res.status();
-        if status.is_client_error() || status.is_server_error() {
-            let text = res.text().await?;
-            tracing::error!("API failed: {}", text);
-            return Err(anyhow::anyhow!("API failed: {}", text));
-        }
+trait Decode {
+    /// Like `Response::json`, but keeps decode failures apart from transport failures.
+    async fn decode<T: DeserializeOwned>(self) -> Result<T>;
+}
 
-        Ok(res)
+#[async_trait]
+impl Decode for Response {
+    async fn decode<T: DeserializeOwned>(self) -> Result<T> {
+        let body = self.bytes().await?;
+        Ok(serde_json::from_slice(&body)?)
     }
 }
 
+#[doc(hidden)]
+pub mod __private {
+    pub use futures::future::BoxFuture;
+    pub use schemars;
+    pub use serde;
+}
+
 /// For tool function. If you have a function that you want ChatGPT to call, you shall put all params into a struct
 /// and derive schmears::JsonSchema for it. Then you use `StructName::to_schema` to generate json schema for tools.
 pub trait ToSchema: JsonSchema {
@@ -122,9 +440,45 @@ fn init() {
     tracing_subscriber::fmt::init()
 }
 
-lazy_static::lazy_static! {
-    static ref SDK:LLmSdk=LLmSdk::new(
-        "https://api.openai.com/v1",
-        std::env::var("OPENAI_API_KEY").unwrap_or("".to_string())
-    );
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prepare_request_should_use_sdk_base_url() -> Result<()> {
+        let sdk = LLmSdk::new("http://localhost:8080/v1", "");
+        let req = sdk
+            .prepare_request(EmbeddingRequest::new("hello"))
+            .build()?;
+        assert_eq!(req.url().as_str(), "http://localhost:8080/v1/embeddings");
+        assert!(req.headers().get("authorization").is_none());
+
+        let sdk = LLmSdk::new("http://gateway.internal/openai", "token");
+        let req = sdk
+            .prepare_request(WhisperRequest::translation(vec![]))
+            .build()?;
+        assert_eq!(
+            req.url().as_str(),
+            "http://gateway.internal/openai/audio/translations"
+        );
+        assert_eq!(req.headers()["authorization"], "Bearer token");
+
+        Ok(())
+    }
+
+    #[test]
+    fn prepare_request_should_keep_custom_authorization() -> Result<()> {
+        let sdk = LLmSdk::builder()
+            .base_url("http://gateway.internal/openai")
+            .token("token")
+            .header("Authorization", "Basic dXNlcjpwYXNz")
+            .build()?;
+        let req = sdk
+            .prepare_request(EmbeddingRequest::new("hello"))
+            .build()?;
+        assert_eq!(req.headers()["authorization"], "Basic dXNlcjpwYXNz");
+        assert_eq!(req.headers().get_all("authorization").iter().count(), 1);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,381 @@
+use crate::{
+    ChatCompleteModel, ChatCompletionMessage, ChatCompletionRequestBuilder, Conversation, Distance,
+    Document, EmbeddingInput, EmbeddingModel, EmbeddingRequestBuilder, LLmSdk, LlmError, Result,
+    TokenCounter, VectorStore,
+};
+use derive_builder::Builder;
+use serde_json::Value;
+
+/// The name of the system message holding the summary, to find it again.
+const SUMMARY_NAME: &str = "conversation_summary";
+
+const DEFAULT_PROMPT: &str = "You maintain the memory of a conversation between a user and an assistant. \
+Merge the previous summary and the new messages into a concise summary. Keep the facts, names, \
+decisions and open questions the assistant needs to carry on the conversation. Reply with the summary only.";
+
+/// Long-term memory of a conversation. When the conversation needs more tokens than `threshold`, its oldest
+/// turns are folded into a rolling summary, a system message right after the system prompt.
+/// The folded turns can also be embedded, to recall them later by similarity.
+#[derive(Debug, Clone, Builder)]
+#[builder(pattern = "mutable")]
+pub struct SummaryMemory {
+    /// Summarize when the conversation needs more tokens than this.
+    threshold: usize,
+    /// The number of recent turns kept verbatim. With 0 every turn is folded into the summary.
+    #[builder(default = "2")]
+    keep_turns: usize,
+    /// The model writing the summary. Defaults to the model of the conversation.
+    #[builder(default, setter(strip_option, into))]
+    model: Option<ChatCompleteModel>,
+    /// The instruction given to the model writing the summary.
+    #[builder(default = "DEFAULT_PROMPT.into()", setter(into))]
+    prompt: String,
+    /// Embed the folded turns, so `recall` can find them again.
+    #[builder(default)]
+    recall: bool,
+    /// The model embedding the folded turns.
+    #[builder(default, setter(into))]
+    embedding_model: EmbeddingModel,
+    #[builder(setter(skip), default = "VectorStore::new(Distance::Cosine)")]
+    memories: VectorStore,
+}
+
+impl SummaryMemory {
+    pub fn new(threshold: usize) -> Self {
+        SummaryMemoryBuilder::default()
+            .threshold(threshold)
+            .build()
+            .unwrap()
+    }
+
+    /// The current summary of `conversation`, if some turns were folded.
+    pub fn summary(conversation: &Conversation) -> Option<&str> {
+        conversation.messages().iter().find_map(|m| match m {
+            ChatCompletionMessage::System(m) if m.name() == Some(SUMMARY_NAME) => Some(m.content()),
+            _ => None,
+        })
+    }
+
+    /// Fold the oldest turns of `conversation` into the summary if it needs more tokens than the threshold.
+    /// Returns whether the conversation was summarized.
+    pub async fn compact(&mut self, sdk: &LLmSdk, conversation: &mut Conversation) -> Result<bool> {
+        let counter = TokenCounter::for_model(conversation.request().model());
+        if counter.count_request(conversation.request()) <= self.threshold {
+            return Ok(false);
+        }
+
+        let messages = conversation.messages();
+        let start = messages
+            .iter()
+            .take_while(|m| matches!(m, ChatCompletionMessage::System(_)))
+            .count();
+        let turns: Vec<_> = (start..messages.len())
+            .filter(|&i| matches!(messages[i], ChatCompletionMessage::User(_)))
+            .collect();
+        if turns.len() <= self.keep_turns {
+            return Ok(false);
+        }
+        let end = match self.keep_turns {
+            0 => messages.len(),
+            n => turns[turns.len() - n],
+        };
+        let previous = messages[..start].iter().position(
+            |m| matches!(m, ChatCompletionMessage::System(m) if m.name() == Some(SUMMARY_NAME)),
+        );
+
+        let summary = self
+            .summarize(sdk, conversation, Self::summary(conversation), start..end)
+            .await?;
+        if self.recall {
+            let folded: Vec<_> = turns
+                .iter()
+                .take_while(|&&i| i < end)
+                .enumerate()
+                .map(|(n, &i)| {
+                    let next = turns.get(n + 1).copied().unwrap_or(end).min(end);
+                    transcript(&messages[i..next])
+                })
+                .collect();
+            self.remember(sdk, folded).await?;
+        }
+
+        conversation.fold(
+            previous,
+            start..end,
+            ChatCompletionMessage::new_system(summary, SUMMARY_NAME),
+        );
+        Ok(true)
+    }
+
+    /// The `k` folded turns most similar to `query`, most similar first. Always empty without `recall`.
+    pub async fn recall(&self, sdk: &LLmSdk, query: &str, k: usize) -> Result<Vec<&str>> {
+        if self.memories.is_empty() || k == 0 {
+            return Ok(vec![]);
+        }
+        let query = self.embed(sdk, vec![query.to_string()]).await?.remove(0);
+        Ok(self
+            .memories
+            .search(&query, k, None)
+            .into_iter()
+            .map(|r| r.document.text.as_str())
+            .collect())
+    }
+
+    async fn summarize(
+        &self,
+        sdk: &LLmSdk,
+        conversation: &Conversation,
+        previous: Option<&str>,
+        range: std::ops::Range<usize>,
+    ) -> Result<String> {
+        let content = format!(
+            "Previous summary:\n{}\n\nNew messages:\n{}",
+            previous.unwrap_or("(none)"),
+            transcript(&conversation.messages()[range])
+        );
+        let model = self
+            .model
+            .clone()
+            .unwrap_or_else(|| conversation.request().model().clone());
+        let req = ChatCompletionRequestBuilder::default()
+            .model(model)
+            .messages(vec![
+                ChatCompletionMessage::new_system(self.prompt.clone(), ""),
+                ChatCompletionMessage::new_user(content, ""),
+            ])
+            .build()
+            .map_err(|e| LlmError::Config(e.to_string()))?;
+        let res = sdk.chat_completion(req).await?;
+        res.choices
+            .first()
+            .and_then(|c| c.message.content())
+            .map(Into::into)
+            .ok_or_else(|| LlmError::Validation("the summary is empty".into()))
+    }
+
+    async fn remember(&mut self, sdk: &LLmSdk, texts: Vec<String>) -> Result<()> {
+        let embeddings = self.embed(sdk, texts.clone()).await?;
+        for (text, embedding) in texts.into_iter().zip(embeddings) {
+            let id = self.memories.len().to_string();
+            self.memories.insert(Document::new(id, text), embedding)?;
+        }
+        Ok(())
+    }
+
+    async fn embed(&self, sdk: &LLmSdk, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let req = EmbeddingRequestBuilder::default()
+            .input(EmbeddingInput::from(texts))
+            .model(self.embedding_model.clone())
+            .build()
+            .map_err(|e| LlmError::Config(e.to_string()))?;
+        let mut data = sdk.embedding(req).await?.data;
+        data.sort_by_key(|d| d.index());
+        Ok(data.into_iter().map(|d| d.into_embedding()).collect())
+    }
+}
+
+/// Render messages as `role: content` lines for the model writing the summary.
+fn transcript(messages: &[ChatCompletionMessage]) -> String {
+    messages
+        .iter()
+        .filter_map(|m| {
+            let message = serde_json::to_value(m).ok()?;
+            let role = message["role"].as_str().unwrap_or_default().to_string();
+            let mut text = match &message["content"] {
+                Value::String(text) => text.clone(),
+                Value::Array(parts) => parts
+                    .iter()
+                    .map(|p| p["text"].as_str().unwrap_or("[image]"))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                _ => String::new(),
+            };
+            for call in message["tool_calls"].as_array().into_iter().flatten() {
+                let function = &call["function"];
+                text.push_str(&format!(
+                    "[called {}({})]",
+                    function["name"].as_str().unwrap_or_default(),
+                    function["arguments"].as_str().unwrap_or_default()
+                ));
+            }
+            Some(format!("{}: {}", role, text))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{MockResponse, MockServer},
+        Endpoint,
+    };
+
+    fn conversation() -> Conversation {
+        let mut conversation = Conversation::new("You are a helpful assistant.");
+        push_turns(&mut conversation);
+        conversation
+    }
+
+    fn push_turns(conversation: &mut Conversation) {
+        let exchanges = [
+            ("My name is Tyr.", "Nice to meet you, Tyr."),
+            ("I live in ShangHai.", "ShangHai is a great city."),
+            ("I like green tea.", "Green tea is healthy."),
+        ];
+        for (question, answer) in exchanges {
+            conversation.push(ChatCompletionMessage::new_user(question, ""));
+            conversation.push(ChatCompletionMessage::Assistant(
+                serde_json::from_value(serde_json::json!({ "content": answer })).unwrap(),
+            ));
+        }
+    }
+
+    #[test]
+    fn transcript_should_render_messages() {
+        let conversation = conversation();
+        assert_eq!(
+            transcript(&conversation.messages()[..3]),
+            "system: You are a helpful assistant.\nuser: My name is Tyr.\nassistant: Nice to meet you, Tyr."
+        );
+    }
+
+    #[test]
+    fn fold_should_replace_turns_with_summary() {
+        let mut conversation = conversation();
+        conversation.fold(
+            None,
+            1..5,
+            ChatCompletionMessage::new_system("Tyr lives in ShangHai.", SUMMARY_NAME),
+        );
+        assert_eq!(conversation.messages().len(), 4);
+        assert_eq!(conversation.turns(), 1);
+        assert_eq!(
+            SummaryMemory::summary(&conversation),
+            Some("Tyr lives in ShangHai.")
+        );
+        assert_eq!(
+            conversation.system_prompt(),
+            Some("You are a helpful assistant.")
+        );
+    }
+
+    #[tokio::test]
+    async fn compact_should_skip_short_conversations() -> Result<()> {
+        let sdk = LLmSdk::new("http://localhost:1", "");
+        let mut conversation = conversation();
+        let mut memory = SummaryMemory::new(1000);
+        assert!(!memory.compact(&sdk, &mut conversation).await?);
+
+        let mut memory = SummaryMemoryBuilder::default()
+            .threshold(10)
+            .keep_turns(3)
+            .build()
+            .unwrap();
+        assert!(!memory.compact(&sdk, &mut conversation).await?);
+        assert_eq!(conversation.messages().len(), 7);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compact_should_fold_every_turn_without_keep_turns() -> Result<()> {
+        let server = MockServer::start().await;
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::chat("Tyr lives in ShangHai and likes green tea."),
+        );
+        let mut conversation = conversation();
+        let mut memory = SummaryMemoryBuilder::default()
+            .threshold(10)
+            .keep_turns(0)
+            .build()
+            .unwrap();
+        assert!(memory.compact(&server.sdk(), &mut conversation).await?);
+        assert_eq!(conversation.messages().len(), 2);
+        assert_eq!(conversation.turns(), 0);
+        assert_eq!(
+            SummaryMemory::summary(&conversation),
+            Some("Tyr lives in ShangHai and likes green tea.")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compact_should_summarize_old_turns() -> Result<()> {
+        let server = MockServer::start().await;
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::chat("Tyr lives in ShangHai."),
+        );
+        let sdk = &server.sdk();
+        let mut conversation = conversation();
+        let mut memory = SummaryMemoryBuilder::default()
+            .threshold(10)
+            .keep_turns(1)
+            .recall(true)
+            .build()
+            .unwrap();
+        assert!(memory.compact(sdk, &mut conversation).await?);
+        assert_eq!(conversation.messages().len(), 4);
+        assert!(SummaryMemory::summary(&conversation)
+            .unwrap()
+            .contains("Tyr"));
+
+        let recalled = memory.recall(sdk, "Where does the user live?", 1).await?;
+        assert!(recalled[0].contains("ShangHai"));
+
+        // the summary request carries the folded turns, and the folded turns were embedded
+        let requests = server.requests();
+        let prompt = requests[0].json().unwrap()["messages"][1]["content"].clone();
+        assert!(prompt
+            .as_str()
+            .unwrap()
+            .contains("user: I live in ShangHai."));
+        let folded = requests[1].json().unwrap()["input"].clone();
+        assert_eq!(folded.as_array().unwrap().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compact_should_replace_the_summary_and_keep_system_messages() -> Result<()> {
+        let server = MockServer::start().await;
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::chat("Tyr lives in ShangHai."),
+        );
+        let mut conversation = Conversation::new("You are a helpful assistant.");
+        conversation.push(ChatCompletionMessage::new_system(
+            "Tyr is a user.",
+            SUMMARY_NAME,
+        ));
+        conversation.push(ChatCompletionMessage::new_system("Answer in English.", ""));
+        push_turns(&mut conversation);
+        let mut memory = SummaryMemoryBuilder::default()
+            .threshold(10)
+            .keep_turns(1)
+            .build()
+            .unwrap();
+        assert!(memory.compact(&server.sdk(), &mut conversation).await?);
+
+        let contents: Vec<_> = conversation.messages()[..3]
+            .iter()
+            .map(|m| serde_json::to_value(m).unwrap()["content"].clone())
+            .collect();
+        assert_eq!(
+            contents,
+            [
+                "You are a helpful assistant.",
+                "Tyr lives in ShangHai.",
+                "Answer in English."
+            ]
+        );
+        assert_eq!(conversation.messages().len(), 5);
+
+        let prompt = server.requests()[0].json().unwrap()["messages"][1]["content"].clone();
+        let prompt = prompt.as_str().unwrap();
+        assert!(prompt.contains("Previous summary:\nTyr is a user."));
+        assert!(!prompt.contains("Answer in English."));
+        Ok(())
+    }
+}
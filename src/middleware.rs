@@ -0,0 +1,177 @@
+use crate::{Cassette, Endpoint, Result};
+use async_trait::async_trait;
+use reqwest::{Client, Request, Response};
+use std::{fmt, sync::Arc};
+
+/// A layer around every request sent by `LLmSdk`, e.g. for custom auth, request signing or metrics.
+///
+/// Middlewares run in the order they were added: the first one sees the request first and the response last.
+/// The request already carries the headers, token and timeout of the sdk, and is sent by the innermost layer
+/// through the client, or the cassette if there is one. Retried requests go through the whole chain again.
+/// Responses are passed through as they are, non-2xx responses are turned into errors after the chain.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    /// Handle `req`, sent to `endpoint`, usually by calling `next.run` with it and returning the response.
+    async fn handle(&self, endpoint: Endpoint, req: Request, next: Next<'_>) -> Result<Response>;
+
+    /// The name shown when debugging `LLmSdk`.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// The rest of the chain, given to `Middleware::handle`.
+pub struct Next<'a> {
+    middlewares: &'a [Arc<dyn Middleware>],
+    client: &'a Client,
+    base_url: &'a str,
+    cassette: Option<&'a Cassette>,
+}
+
+/// The middlewares of an `LLmSdk`.
+#[derive(Clone, Default)]
+pub(crate) struct MiddlewareChain(Vec<Arc<dyn Middleware>>);
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        chain: &'a MiddlewareChain,
+        client: &'a Client,
+        base_url: &'a str,
+        cassette: Option<&'a Cassette>,
+    ) -> Self {
+        Self {
+            middlewares: &chain.0,
+            client,
+            base_url,
+            cassette,
+        }
+    }
+
+    /// Pass `req` to the next middleware, or send it.
+    pub async fn run(self, endpoint: Endpoint, req: Request) -> Result<Response> {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => {
+                let next = Next {
+                    middlewares: rest,
+                    ..self
+                };
+                middleware.handle(endpoint, req, next).await
+            }
+            None => match self.cassette {
+                Some(cassette) => cassette.send(self.client, self.base_url, req).await,
+                None => Ok(self.client.execute(req).await?),
+            },
+        }
+    }
+}
+
+impl MiddlewareChain {
+    pub(crate) fn push(&mut self, middleware: Arc<dyn Middleware>) {
+        self.0.push(middleware);
+    }
+}
+
+impl fmt::Debug for MiddlewareChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|m| m.name()))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::MockServer, EmbeddingRequest, LLmSdk, LlmError};
+    use reqwest::header::HeaderValue;
+    use std::sync::Mutex;
+
+    /// Adds a header and records the order it ran in, and the statuses it saw.
+    struct Tag {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Middleware for Tag {
+        async fn handle(
+            &self,
+            endpoint: Endpoint,
+            mut req: Request,
+            next: Next<'_>,
+        ) -> Result<Response> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} {:?}", self.name, endpoint));
+            let value = match req.headers().get("x-tags") {
+                Some(tags) => format!("{},{}", tags.to_str().unwrap(), self.name),
+                None => self.name.to_string(),
+            };
+            req.headers_mut()
+                .insert("x-tags", HeaderValue::from_str(&value).unwrap());
+            let res = next.run(endpoint, req).await?;
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", self.name, res.status().as_u16()));
+            Ok(res)
+        }
+    }
+
+    struct Deny;
+
+    #[async_trait]
+    impl Middleware for Deny {
+        async fn handle(&self, _: Endpoint, _: Request, _: Next<'_>) -> Result<Response> {
+            Err(LlmError::Config("denied".into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn middlewares_should_run_in_order() -> Result<()> {
+        let server = MockServer::start().await;
+        let log = Arc::new(Mutex::new(vec![]));
+        let tag = |name| Tag {
+            name,
+            log: log.clone(),
+        };
+        let sdk = server
+            .sdk()
+            .with_middleware(tag("outer"))
+            .with_middleware(tag("inner"));
+        sdk.embedding(EmbeddingRequest::new("hi")).await?;
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "outer Embeddings",
+                "inner Embeddings",
+                "inner 200",
+                "outer 200"
+            ]
+        );
+        assert_eq!(server.requests()[0].headers["x-tags"], "outer,inner");
+        assert!(format!("{:?}", sdk).contains("Tag"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn middleware_should_short_circuit() {
+        let server = MockServer::start().await;
+        let sdk = server.sdk().with_middleware(Deny);
+        let err = sdk
+            .embedding(EmbeddingRequest::new("hi"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, LlmError::Config(_)));
+        assert!(server.requests().is_empty());
+
+        let sdk = LLmSdk::builder()
+            .base_url(server.url())
+            .middleware(Deny)
+            .build()
+            .unwrap();
+        assert!(sdk.embedding(EmbeddingRequest::new("hi")).await.is_err());
+    }
+}
@@ -0,0 +1,329 @@
+use crate::ChatCompletionUsage;
+use derive_builder::Builder;
+
+/// Tokens of completion reserved by `ModelInfo::prompt_budget` when the request doesn't set `max_tokens`.
+const DEFAULT_COMPLETION_TOKENS: usize = 1024;
+
+/// Define a model id type: any string is accepted, the known models are constants.
+macro_rules! model_id {
+    (
+        $(#[$meta:meta])*
+        $name:ident, default = $default:ident {
+            $($(#[$const_meta:meta])* $const:ident = $id:literal),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+        #[serde(transparent)]
+        pub struct $name(std::borrow::Cow<'static, str>);
+
+        impl $name {
+            $(
+                $(#[$const_meta])*
+                pub const $const: $name = $name(std::borrow::Cow::Borrowed($id));
+            )*
+
+            /// A model the sdk doesn't know, e.g. a fine-tuned model or one served by an OpenAI-compatible server.
+            pub fn new(id: impl Into<String>) -> Self {
+                Self(std::borrow::Cow::Owned(id.into()))
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                Self::$default
+            }
+        }
+
+        impl From<&str> for $name {
+            fn from(id: &str) -> Self {
+                Self::new(id)
+            }
+        }
+
+        impl From<String> for $name {
+            fn from(id: String) -> Self {
+                Self::new(id)
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                self.as_str()
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+    };
+}
+
+pub(crate) use model_id;
+
+/// The kinds of input a model accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Modality {
+    Text,
+    Image,
+    Audio,
+}
+
+/// Price of a model in USD per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPricing {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl ModelPricing {
+    pub const fn new(input: f64, output: f64) -> Self {
+        Self { input, output }
+    }
+
+    /// The cost in USD of a chat completion.
+    pub fn cost(&self, usage: &ChatCompletionUsage) -> f64 {
+        (usage.prompt_tokens as f64 * self.input + usage.completion_tokens as f64 * self.output)
+            / 1_000_000.0
+    }
+}
+
+/// What the sdk knows about a model.
+#[derive(Debug, Clone, PartialEq, Builder)]
+#[builder(pattern = "mutable")]
+pub struct ModelInfo {
+    /// The model id, also matching its dated snapshots, e.g. `gpt-4o` matches `gpt-4o-2024-08-06`.
+    #[builder(setter(into))]
+    pub id: String,
+    /// Max number of tokens of the prompt and the completion together.
+    pub context_window: usize,
+    /// Max number of tokens the model can generate, when it's lower than the context window.
+    #[builder(default, setter(strip_option))]
+    pub max_output_tokens: Option<usize>,
+    /// The kinds of input the model accepts.
+    #[builder(default = "vec![Modality::Text]")]
+    pub modalities: Vec<Modality>,
+    /// Whether the model supports function calling.
+    #[builder(default)]
+    pub supports_tools: bool,
+    /// Whether the model supports the `json_schema` response format.
+    #[builder(default)]
+    pub supports_structured_outputs: bool,
+    #[builder(default, setter(strip_option))]
+    pub pricing: Option<ModelPricing>,
+}
+
+impl ModelInfo {
+    pub fn supports(&self, modality: Modality) -> bool {
+        self.modalities.contains(&modality)
+    }
+
+    /// Max number of tokens the model can generate.
+    pub fn max_output_tokens(&self) -> usize {
+        self.max_output_tokens.unwrap_or(self.context_window)
+    }
+
+    /// Max number of prompt tokens, leaving room for `max_tokens` tokens of completion.
+    /// Without `max_tokens`, 1024 tokens are reserved, or a quarter of the context window if it's smaller.
+    pub fn prompt_budget(&self, max_tokens: Option<usize>) -> usize {
+        let reserved = max_tokens
+            .unwrap_or(DEFAULT_COMPLETION_TOKENS.min(self.context_window / 4))
+            .min(self.max_output_tokens());
+        self.context_window.saturating_sub(reserved)
+    }
+}
+
+/// Metadata of the models in use, prefilled with the OpenAI models. Register your own models with `register`.
+#[derive(Debug, Clone)]
+pub struct ModelRegistry {
+    models: Vec<ModelInfo>,
+}
+
+impl Default for ModelRegistry {
+    fn default() -> Self {
+        use Modality::*;
+
+        let chat = |id: &str, context_window, max_output_tokens, modalities: &[Modality]| {
+            ModelInfoBuilder::default()
+                .id(id)
+                .context_window(context_window)
+                .max_output_tokens(max_output_tokens)
+                .modalities(modalities.to_vec())
+                .supports_tools(true)
+                .clone()
+        };
+        let embedding = |id: &str, price| {
+            ModelInfoBuilder::default()
+                .id(id)
+                .context_window(8191)
+                .pricing(ModelPricing::new(price, 0.0))
+                .build()
+                .unwrap()
+        };
+
+        let models = vec![
+            chat("gpt-3.5-turbo", 16385, 4096, &[Text])
+                .pricing(ModelPricing::new(0.5, 1.5))
+                .build(),
+            chat("gpt-3.5-turbo-instruct", 4096, 4096, &[Text])
+                .supports_tools(false)
+                .pricing(ModelPricing::new(1.5, 2.0))
+                .build(),
+            chat("gpt-4", 8192, 8192, &[Text])
+                .pricing(ModelPricing::new(30.0, 60.0))
+                .build(),
+            chat("gpt-4-1106-preview", 128000, 4096, &[Text])
+                .pricing(ModelPricing::new(10.0, 30.0))
+                .build(),
+            chat("gpt-4-1106-vision-preview", 128000, 4096, &[Text, Image])
+                .supports_tools(false)
+                .pricing(ModelPricing::new(10.0, 30.0))
+                .build(),
+            chat("gpt-4-turbo", 128000, 4096, &[Text, Image])
+                .pricing(ModelPricing::new(10.0, 30.0))
+                .build(),
+            chat("gpt-4o", 128000, 16384, &[Text, Image])
+                .supports_structured_outputs(true)
+                .pricing(ModelPricing::new(2.5, 10.0))
+                .build(),
+            chat("gpt-4o-mini", 128000, 16384, &[Text, Image])
+                .supports_structured_outputs(true)
+                .pricing(ModelPricing::new(0.15, 0.6))
+                .build(),
+            chat("o1", 200000, 100000, &[Text, Image])
+                .supports_structured_outputs(true)
+                .pricing(ModelPricing::new(15.0, 60.0))
+                .build(),
+            chat("o3-mini", 200000, 100000, &[Text])
+                .supports_structured_outputs(true)
+                .pricing(ModelPricing::new(1.1, 4.4))
+                .build(),
+            chat("babbage-002", 16384, 16384, &[Text])
+                .supports_tools(false)
+                .pricing(ModelPricing::new(0.4, 0.4))
+                .build(),
+        ];
+        let mut models: Vec<_> = models.into_iter().map(Result::unwrap).collect();
+        models.extend([
+            embedding("text-embedding-ada-002", 0.1),
+            embedding("text-embedding-3-small", 0.02),
+            embedding("text-embedding-3-large", 0.13),
+        ]);
+        Self { models }
+    }
+}
+
+impl ModelRegistry {
+    /// A registry without any model.
+    pub fn empty() -> Self {
+        Self { models: vec![] }
+    }
+
+    /// Add a model, replacing the one with the same id.
+    pub fn register(&mut self, info: ModelInfo) -> &mut Self {
+        self.models.retain(|m| m.id != info.id);
+        self.models.push(info);
+        self
+    }
+
+    /// Find a model by id. Dated snapshots like `gpt-4o-2024-08-06` fall back to the longest
+    /// registered id they start with, `gpt-4o` here.
+    pub fn get(&self, id: impl AsRef<str>) -> Option<&ModelInfo> {
+        let id = id.as_ref();
+        self.models
+            .iter()
+            .filter(|m| {
+                id == m.id
+                    || id
+                        .strip_prefix(m.id.as_str())
+                        .is_some_and(|rest| rest.starts_with('-'))
+            })
+            .max_by_key(|m| m.id.len())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ModelInfo> {
+        self.models.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChatCompleteModel;
+
+    #[test]
+    fn model_registry_get_should_match_snapshots() {
+        let registry = ModelRegistry::default();
+        assert_eq!(registry.get("gpt-4o").unwrap().context_window, 128000);
+        assert_eq!(
+            registry.get("gpt-4o-mini-2024-07-18").unwrap().id,
+            "gpt-4o-mini"
+        );
+        assert_eq!(
+            registry.get(ChatCompleteModel::GPT3_TURBO).unwrap().id,
+            "gpt-3.5-turbo"
+        );
+        assert_eq!(registry.get("gpt-4-0613").unwrap().id, "gpt-4");
+        assert!(registry.get("gpt-4ox").is_none());
+        assert!(registry.get("llama3").is_none());
+
+        let info = registry.get("gpt-4-1106-vision-preview").unwrap();
+        assert!(info.supports(Modality::Image));
+        assert!(!registry.get("gpt-4").unwrap().supports(Modality::Image));
+    }
+
+    #[test]
+    fn model_registry_register_should_work() {
+        let mut registry = ModelRegistry::default();
+        registry.register(
+            ModelInfoBuilder::default()
+                .id("llama3")
+                .context_window(8192)
+                .build()
+                .unwrap(),
+        );
+        let info = registry.get("llama3").unwrap();
+        assert_eq!(info.max_output_tokens(), 8192);
+        assert_eq!(info.pricing, None);
+    }
+
+    #[test]
+    fn model_info_prompt_budget_should_reserve_completion() {
+        let registry = ModelRegistry::default();
+        let info = registry.get("gpt-4o").unwrap();
+        assert_eq!(info.prompt_budget(None), 128000 - 1024);
+        assert_eq!(info.prompt_budget(Some(100)), 128000 - 100);
+        assert_eq!(
+            info.prompt_budget(Some(1_000_000)),
+            128000 - info.max_output_tokens()
+        );
+
+        let tiny = ModelInfoBuilder::default()
+            .id("tiny")
+            .context_window(40)
+            .build()
+            .unwrap();
+        assert_eq!(tiny.prompt_budget(None), 30);
+    }
+
+    #[test]
+    fn model_pricing_cost_should_work() {
+        let pricing = ModelRegistry::default()
+            .get("gpt-4o")
+            .unwrap()
+            .pricing
+            .unwrap();
+        let usage = ChatCompletionUsage {
+            completion_tokens: 1000,
+            prompt_tokens: 2000,
+            total_tokens: 3000,
+        };
+        assert!((pricing.cost(&usage) - 0.015).abs() < 1e-9);
+    }
+}
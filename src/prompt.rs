@@ -0,0 +1,594 @@
+use crate::{ChatCompletionMessage, LlmError, Result};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{collections::BTreeSet, path::Path};
+
+/// A prompt rendered into chat messages, with `{{variable}}` interpolation.
+///
+/// - `{{name}}` and `{{user.name}}` insert a variable.
+/// - `{{#if name}}..{{else}}..{{/if}}` renders a branch depending on whether the variable is truthy:
+///   not false, null, 0, an empty string or an empty list.
+/// - `{{#each items as item}}..{{/each}}` renders the block for every item, `{{@index}}` is its index.
+/// - `{{#system}}..{{/system}}`, `{{#user}}..{{/user}}` and `{{#assistant}}..{{/assistant}}` are messages.
+///   A template without them is a single system message.
+/// - `{{#examples shots}}` adds a user and an assistant message for every `{user, assistant}` item of `shots`,
+///   for few-shot prompting.
+/// - `{{! comment }}` is ignored.
+///
+/// Rendering fails if a variable the template uses is missing, or if the variables contain one it doesn't use.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptTemplate {
+    nodes: Vec<Node>,
+    variables: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Var(Vec<String>),
+    If {
+        cond: Vec<String>,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    Each {
+        items: Vec<String>,
+        item: String,
+        body: Vec<Node>,
+    },
+    Message(Role, Vec<Node>),
+    Examples(Vec<String>),
+}
+
+impl PromptTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(template)?,
+            pos: 0,
+        };
+        let nodes = parser.parse_block(None)?;
+        check_messages(&nodes, false, nodes.iter().any(Node::is_message))?;
+        let mut variables = BTreeSet::new();
+        collect_variables(&nodes, &mut vec![], &mut variables);
+        Ok(Self { nodes, variables })
+    }
+
+    /// Read and parse the template in `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let template = std::fs::read_to_string(path)
+            .map_err(|e| LlmError::Template(format!("failed to read {}: {}", path.display(), e)))?;
+        Self::parse(&template)
+    }
+
+    /// The names of the variables the template uses.
+    pub fn variables(&self) -> impl Iterator<Item = &str> {
+        self.variables.iter().map(String::as_str)
+    }
+
+    /// Render the template with `vars`, a struct or a map whose fields are the variables.
+    pub fn render(&self, vars: &impl Serialize) -> Result<Vec<ChatCompletionMessage>> {
+        let vars = match serde_json::to_value(vars)? {
+            Value::Object(vars) => vars,
+            Value::Null => Map::new(),
+            _ => {
+                return Err(LlmError::Template(
+                    "variables must be a struct or a map".into(),
+                ))
+            }
+        };
+        if let Some(unknown) = vars.keys().find(|k| !self.variables.contains(*k)) {
+            return Err(LlmError::Template(format!(
+                "unknown variable `{}`",
+                unknown
+            )));
+        }
+
+        let mut renderer = Renderer {
+            vars: &vars,
+            scopes: vec![],
+            messages: vec![],
+        };
+        let has_messages = self.nodes.iter().any(Node::is_message);
+        if has_messages {
+            renderer.render_nodes(&self.nodes, &mut String::new())?;
+        } else {
+            let mut text = String::new();
+            renderer.render_nodes(&self.nodes, &mut text)?;
+            renderer.push(Role::System, text);
+        }
+        Ok(renderer.messages)
+    }
+}
+
+impl Node {
+    fn is_message(&self) -> bool {
+        matches!(self, Node::Message(..) | Node::Examples(_))
+            || self
+                .children()
+                .any(|nodes| nodes.iter().any(Node::is_message))
+    }
+
+    fn children(&self) -> impl Iterator<Item = &Vec<Node>> {
+        let children: Vec<_> = match self {
+            Node::If {
+                then, otherwise, ..
+            } => vec![then, otherwise],
+            Node::Each { body, .. } | Node::Message(_, body) => vec![body],
+            Node::Text(_) | Node::Var(_) | Node::Examples(_) => vec![],
+        };
+        children.into_iter()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Text(String),
+    Tag(String),
+}
+
+fn tokenize(template: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        if start > 0 {
+            tokens.push(Token::Text(rest[..start].to_string()));
+        }
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| LlmError::Template("unclosed `{{`".into()))?;
+        let tag = rest[start + 2..start + end].trim();
+        if !tag.starts_with('!') {
+            tokens.push(Token::Tag(tag.to_string()));
+        }
+        rest = &rest[start + end + 2..];
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest.to_string()));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    /// Parse nodes until the closing tag of `block`, or the end of the template at the top level.
+    fn parse_block(&mut self, block: Option<&str>) -> Result<Vec<Node>> {
+        match self.parse_branch(block)? {
+            (_, true) => Err(LlmError::Template(
+                "more than one `{{else}}` in `{{#if}}`".into(),
+            )),
+            (nodes, false) => Ok(nodes),
+        }
+    }
+
+    /// Like `parse_block`, but also stops after `{{else}}` in `if` blocks.
+    /// Returns whether it stopped at `{{else}}` rather than at the closing tag.
+    fn parse_branch(&mut self, block: Option<&str>) -> Result<(Vec<Node>, bool)> {
+        let mut nodes = vec![];
+        while let Some(token) = self.tokens.get(self.pos) {
+            self.pos += 1;
+            let tag = match token {
+                Token::Text(text) => {
+                    nodes.push(Node::Text(text.clone()));
+                    continue;
+                }
+                Token::Tag(tag) => tag.clone(),
+            };
+
+            if let Some(name) = tag.strip_prefix('/') {
+                return match block {
+                    Some(block) if block == name.trim() => Ok((nodes, false)),
+                    Some(block) => Err(LlmError::Template(format!(
+                        "expected `{{{{/{}}}}}`, found `{{{{{}}}}}`",
+                        block, tag
+                    ))),
+                    None => Err(LlmError::Template(format!("unexpected `{{{{{}}}}}`", tag))),
+                };
+            }
+            if tag == "else" {
+                if block != Some("if") {
+                    return Err(LlmError::Template("`{{else}}` outside of `{{#if}}`".into()));
+                }
+                return Ok((nodes, true));
+            }
+            let Some(open) = tag.strip_prefix('#') else {
+                nodes.push(Node::Var(path(&tag)?));
+                continue;
+            };
+
+            let mut words = open.split_whitespace();
+            let name = words.next().unwrap_or_default();
+            let args: Vec<_> = words.collect();
+            let node = match (name, args.as_slice()) {
+                ("if", [cond]) => {
+                    let (then, has_else) = self.parse_branch(Some("if"))?;
+                    let otherwise = match has_else {
+                        true => self.parse_block(Some("if"))?,
+                        false => vec![],
+                    };
+                    Node::If {
+                        cond: path(cond)?,
+                        then,
+                        otherwise,
+                    }
+                }
+                ("each", [items, "as", item]) => Node::Each {
+                    items: path(items)?,
+                    item: ident(item)?.to_string(),
+                    body: self.parse_block(Some("each"))?,
+                },
+                ("system", []) => Node::Message(Role::System, self.parse_block(Some("system"))?),
+                ("user", []) => Node::Message(Role::User, self.parse_block(Some("user"))?),
+                ("assistant", []) => {
+                    Node::Message(Role::Assistant, self.parse_block(Some("assistant"))?)
+                }
+                ("examples", [items]) => Node::Examples(path(items)?),
+                _ => return Err(LlmError::Template(format!("invalid tag `{{{{{}}}}}`", tag))),
+            };
+            nodes.push(node);
+        }
+        match block {
+            Some(block) => Err(LlmError::Template(format!("missing `{{{{/{}}}}}`", block))),
+            None => Ok((nodes, false)),
+        }
+    }
+}
+
+fn path(path: &str) -> Result<Vec<String>> {
+    path.split('.')
+        .map(|segment| match segment {
+            "@index" => Ok(segment.to_string()),
+            _ => ident(segment).map(Into::into),
+        })
+        .collect()
+}
+
+fn ident(name: &str) -> Result<&str> {
+    let valid = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match valid {
+        true => Ok(name),
+        false => Err(LlmError::Template(format!("invalid name `{}`", name))),
+    }
+}
+
+/// Messages can't be nested, and text outside of them must be blank when the template has messages.
+fn check_messages(nodes: &[Node], in_message: bool, has_messages: bool) -> Result<()> {
+    for node in nodes {
+        match node {
+            Node::Message(..) | Node::Examples(_) if in_message => {
+                return Err(LlmError::Template("messages can't be nested".into()));
+            }
+            Node::Message(_, body) => check_messages(body, true, has_messages)?,
+            Node::Text(text) if has_messages && !in_message && !text.trim().is_empty() => {
+                return Err(LlmError::Template(format!(
+                    "text outside of a message: {:?}",
+                    text.trim()
+                )));
+            }
+            Node::Var(path) if has_messages && !in_message => {
+                return Err(LlmError::Template(format!(
+                    "variable `{}` outside of a message",
+                    path.join(".")
+                )));
+            }
+            node => {
+                for children in node.children() {
+                    check_messages(children, in_message, has_messages)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Collect the root variables used by `nodes`, the loop variables in `scopes` aside.
+fn collect_variables(nodes: &[Node], scopes: &mut Vec<String>, variables: &mut BTreeSet<String>) {
+    for node in nodes {
+        let root = match node {
+            Node::Var(path) | Node::Examples(path) | Node::If { cond: path, .. } => Some(&path[0]),
+            Node::Each { items, .. } => Some(&items[0]),
+            Node::Text(_) | Node::Message(..) => None,
+        };
+        if let Some(root) = root.filter(|root| *root != "@index" && !scopes.contains(root)) {
+            variables.insert(root.clone());
+        }
+        if let Node::Each { item, body, .. } = node {
+            scopes.push(item.clone());
+            collect_variables(body, scopes, variables);
+            scopes.pop();
+            continue;
+        }
+        for children in node.children() {
+            collect_variables(children, scopes, variables);
+        }
+    }
+}
+
+struct Renderer<'a> {
+    vars: &'a Map<String, Value>,
+    /// The loop variables, innermost last, with the index of their item.
+    scopes: Vec<(String, Value, usize)>,
+    messages: Vec<ChatCompletionMessage>,
+}
+
+impl Renderer<'_> {
+    fn render_nodes(&mut self, nodes: &[Node], out: &mut String) -> Result<()> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Var(path) => match self.lookup(path)? {
+                    Value::String(s) => out.push_str(&s),
+                    value @ (Value::Number(_) | Value::Bool(_)) => out.push_str(&value.to_string()),
+                    Value::Null => {
+                        return Err(LlmError::Template(format!(
+                            "variable `{}` is null",
+                            path.join(".")
+                        )))
+                    }
+                    _ => {
+                        return Err(LlmError::Template(format!(
+                            "variable `{}` is not a string, a number or a bool",
+                            path.join(".")
+                        )))
+                    }
+                },
+                Node::If {
+                    cond,
+                    then,
+                    otherwise,
+                } => match truthy(&self.lookup(cond)?) {
+                    true => self.render_nodes(then, out)?,
+                    false => self.render_nodes(otherwise, out)?,
+                },
+                Node::Each { items, item, body } => {
+                    for (index, value) in self.list(items)?.into_iter().enumerate() {
+                        self.scopes.push((item.clone(), value, index));
+                        let ret = self.render_nodes(body, out);
+                        self.scopes.pop();
+                        ret?;
+                    }
+                }
+                Node::Message(role, body) => {
+                    let mut text = String::new();
+                    self.render_nodes(body, &mut text)?;
+                    self.push(*role, text);
+                }
+                Node::Examples(items) => {
+                    for example in self.list(items)? {
+                        for (role, key) in [(Role::User, "user"), (Role::Assistant, "assistant")] {
+                            let text = example[key].as_str().ok_or_else(|| {
+                                LlmError::Template(format!(
+                                    "examples of `{}` need a `{}` string",
+                                    items.join("."),
+                                    key
+                                ))
+                            })?;
+                            self.push(role, text.to_string());
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, role: Role, text: String) {
+        let text = text.trim().to_string();
+        let message = match role {
+            Role::System => ChatCompletionMessage::new_system(text, ""),
+            Role::User => ChatCompletionMessage::new_user(text, ""),
+            Role::Assistant => ChatCompletionMessage::new_assistant(text),
+        };
+        self.messages.push(message);
+    }
+
+    fn lookup(&self, path: &[String]) -> Result<Value> {
+        let missing = || LlmError::Template(format!("missing variable `{}`", path.join(".")));
+        let scope = self.scopes.iter().rev().find(|(name, ..)| *name == path[0]);
+        let mut value = match (path[0].as_str(), scope) {
+            ("@index", _) => {
+                let (.., index) = self.scopes.last().ok_or_else(missing)?;
+                return Ok(Value::from(*index));
+            }
+            (_, Some((_, value, _))) => value,
+            (name, None) => self.vars.get(name).ok_or_else(missing)?,
+        };
+        for key in &path[1..] {
+            value = value.get(key).ok_or_else(missing)?;
+        }
+        Ok(value.clone())
+    }
+
+    fn list(&self, path: &[String]) -> Result<Vec<Value>> {
+        match self.lookup(path)? {
+            Value::Array(items) => Ok(items),
+            Value::Null => Ok(vec![]),
+            _ => Err(LlmError::Template(format!(
+                "variable `{}` is not a list",
+                path.join(".")
+            ))),
+        }
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Vars {
+        bot: String,
+        user: User,
+        formal: bool,
+        topics: Vec<String>,
+    }
+
+    #[derive(Serialize)]
+    struct User {
+        name: String,
+    }
+
+    fn vars() -> Vars {
+        Vars {
+            bot: "Q".into(),
+            user: User { name: "Tyr".into() },
+            formal: false,
+            topics: vec!["rust".into(), "tea".into()],
+        }
+    }
+
+    fn contents(messages: &[ChatCompletionMessage]) -> Vec<(String, String)> {
+        messages
+            .iter()
+            .map(|m| {
+                let json = serde_json::to_value(m).unwrap();
+                (
+                    json["role"].as_str().unwrap().to_string(),
+                    json["content"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn render_should_interpolate_variables() -> Result<()> {
+        let template = PromptTemplate::parse(
+            "You are {{ bot }}, talking to {{user.name}}.\n\
+             {{#if formal}}Be formal.{{else}}Be casual.{{/if}}\n\
+             {{! topics }}Topics:{{#each topics as topic}} {{@index}}.{{topic}}{{/each}}",
+        )?;
+        assert_eq!(
+            template.variables().collect::<Vec<_>>(),
+            ["bot", "formal", "topics", "user"]
+        );
+        assert_eq!(
+            contents(&template.render(&vars())?),
+            [(
+                "system".to_string(),
+                "You are Q, talking to Tyr.\nBe casual.\nTopics: 0.rust 1.tea".to_string()
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn render_should_build_messages_and_examples() -> Result<()> {
+        let template = PromptTemplate::parse(
+            "{{#system}}Translate to {{ language }}.{{/system}}\n\
+             {{#examples shots}}\n\
+             {{#user}}{{ text }}{{/user}}",
+        )?;
+        let vars = serde_json::json!({
+            "language": "French",
+            "shots": [{ "user": "cat", "assistant": "chat" }],
+            "text": "dog",
+        });
+        assert_eq!(
+            contents(&template.render(&vars)?),
+            [
+                ("system".to_string(), "Translate to French.".to_string()),
+                ("user".to_string(), "cat".to_string()),
+                ("assistant".to_string(), "chat".to_string()),
+                ("user".to_string(), "dog".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn render_should_pair_nested_if_and_else() -> Result<()> {
+        let template =
+            PromptTemplate::parse("{{#user}}{{#if a}}{{#if b}}x{{/if}}{{else}}y{{/if}}{{/user}}")?;
+        let render = |a: bool, b: bool| -> Result<_> {
+            let messages = template.render(&serde_json::json!({ "a": a, "b": b }))?;
+            Ok(contents(&messages)[0].1.clone())
+        };
+        assert_eq!(render(true, true)?, "x");
+        assert_eq!(render(true, false)?, "");
+        assert_eq!(render(false, true)?, "y");
+        Ok(())
+    }
+
+    #[test]
+    fn render_should_fail_on_missing_or_unknown_variables() -> Result<()> {
+        let template = PromptTemplate::parse("Hi {{ user.name }}, I'm {{ bot }}.")?;
+        let err = template
+            .render(&serde_json::json!({ "user": { "name": "Tyr" } }))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "template error: missing variable `bot`");
+
+        let err = template
+            .render(&serde_json::json!({ "user": {}, "bot": "Q" }))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "template error: missing variable `user.name`"
+        );
+
+        let err = template
+            .render(&serde_json::json!({ "user": { "name": "Tyr" }, "bot": "Q", "mood": "happy" }))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "template error: unknown variable `mood`");
+        Ok(())
+    }
+
+    #[test]
+    fn parse_should_reject_invalid_templates() {
+        for template in [
+            "{{#if a}}open",
+            "{{#each items}}{{/each}}",
+            "{{#if a}}{{/each}}",
+            "{{ a b }}",
+            "{{ a",
+            "{{else}}",
+            "{{#if a}}x{{else}}y{{else}}z{{/if}}",
+            "text {{#user}}hi{{/user}}",
+            "{{#user}}{{#system}}hi{{/system}}{{/user}}",
+            "{{#if a}}text{{/if}}{{#user}}hi{{/user}}",
+        ] {
+            assert!(
+                matches!(PromptTemplate::parse(template), Err(LlmError::Template(_))),
+                "{}",
+                template
+            );
+        }
+    }
+
+    #[test]
+    fn from_file_should_work() -> Result<()> {
+        let template = PromptTemplate::from_file("fixtures/prompt.txt")?;
+        let messages = template.render(&serde_json::json!({
+            "bot": "Q",
+            "shots": [{ "user": "Hi!", "assistant": "Hello!" }],
+            "question": "How are you?",
+        }))?;
+        assert_eq!(messages.len(), 4);
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+use crate::{
+    ChatCompleteModel, ChatCompletionMessage, ChatCompletionRequest, ChatCompletionRequestBuilder,
+    ChatCompletionUsage, Document, EmbeddingInput, EmbeddingModel, EmbeddingRequestBuilder, Filter,
+    LLmSdk, LlmError, Result, SearchResult, VectorStore,
+};
+use async_trait::async_trait;
+use std::{fmt, sync::Arc};
+
+const DEFAULT_PROMPT: &str = "Answer the question using only the numbered sources below. \
+Cite the sources you use with their number in brackets, like [1]. \
+If the sources don't contain the answer, say that you don't know.";
+
+/// Finds the chunks relevant to a query, for `Rag`.
+#[async_trait]
+pub trait Retriever: Send + Sync {
+    /// The `k` chunks most relevant to `query`, whose embedding is `embedding`, most relevant first.
+    async fn retrieve(&self, query: &str, embedding: &[f32], k: usize) -> Result<Vec<Source>>;
+}
+
+/// A chunk retrieved for a query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub document: Document,
+    /// The similarity of the chunk to the query, higher is more similar.
+    pub score: f32,
+}
+
+/// The answer of `Rag::answer`.
+#[derive(Debug, Clone)]
+pub struct RagAnswer {
+    pub answer: String,
+    /// The retrieved chunks given to the model, `[1]` being the first one.
+    pub sources: Vec<Source>,
+    pub usage: ChatCompletionUsage,
+}
+
+/// Retrieval-augmented generation: answers questions from the chunks a `Retriever` finds for them,
+/// given to the model as numbered sources to cite.
+#[derive(Clone)]
+pub struct Rag {
+    retriever: Arc<dyn Retriever>,
+    k: usize,
+    min_score: Option<f32>,
+    model: ChatCompleteModel,
+    embedding_model: EmbeddingModel,
+    prompt: String,
+}
+
+impl Rag {
+    /// Answer from the 4 chunks most relevant to the question.
+    pub fn new(retriever: impl Retriever + 'static) -> Self {
+        Self {
+            retriever: Arc::new(retriever),
+            k: 4,
+            min_score: None,
+            model: ChatCompleteModel::default(),
+            embedding_model: EmbeddingModel::default(),
+            prompt: DEFAULT_PROMPT.into(),
+        }
+    }
+
+    /// The number of chunks retrieved for a question.
+    pub fn with_k(mut self, k: usize) -> Self {
+        self.k = k;
+        self
+    }
+
+    /// Leave out the chunks scoring less than `min_score`.
+    pub fn with_min_score(mut self, min_score: f32) -> Self {
+        self.min_score = Some(min_score);
+        self
+    }
+
+    /// The model answering the question.
+    pub fn with_model(mut self, model: impl Into<ChatCompleteModel>) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    /// The model embedding the question, which must be the one that embedded the chunks.
+    pub fn with_embedding_model(mut self, model: impl Into<EmbeddingModel>) -> Self {
+        self.embedding_model = model.into();
+        self
+    }
+
+    /// The instruction to the model, followed by the sources in the system message.
+    pub fn with_prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.prompt = prompt.into();
+        self
+    }
+
+    /// Retrieve the sources of `query` and build the request answering it, to customize or stream it.
+    pub async fn prepare(
+        &self,
+        sdk: &LLmSdk,
+        query: &str,
+    ) -> Result<(ChatCompletionRequest, Vec<Source>)> {
+        let req = EmbeddingRequestBuilder::default()
+            .input(EmbeddingInput::from(query))
+            .model(self.embedding_model.clone())
+            .build()
+            .map_err(|e| LlmError::Config(e.to_string()))?;
+        let embedding = sdk
+            .embedding(req)
+            .await?
+            .data
+            .into_iter()
+            .next()
+            .map(|d| d.into_embedding())
+            .ok_or_else(|| LlmError::Validation("the query embedding is missing".into()))?;
+
+        let mut sources = self.retriever.retrieve(query, &embedding, self.k).await?;
+        sources.truncate(self.k);
+        if let Some(min_score) = self.min_score {
+            sources.retain(|s| s.score >= min_score);
+        }
+
+        let req = ChatCompletionRequestBuilder::default()
+            .model(self.model.clone())
+            .messages(vec![
+                ChatCompletionMessage::new_system(self.system_message(&sources), ""),
+                ChatCompletionMessage::new_user(query, ""),
+            ])
+            .build()
+            .map_err(|e| LlmError::Config(e.to_string()))?;
+        Ok((req, sources))
+    }
+
+    /// Answer `query` from the chunks retrieved for it.
+    pub async fn answer(&self, sdk: &LLmSdk, query: &str) -> Result<RagAnswer> {
+        let (req, sources) = self.prepare(sdk, query).await?;
+        let res = sdk.chat_completion(req).await?;
+        let answer = res
+            .choices
+            .first()
+            .and_then(|c| c.message.content())
+            .map(String::from)
+            .ok_or_else(|| LlmError::Validation("the answer is empty".into()))?;
+        Ok(RagAnswer {
+            answer,
+            sources,
+            usage: res.usage,
+        })
+    }
+
+    fn system_message(&self, sources: &[Source]) -> String {
+        let mut message = format!("{}\n\nSources:", self.prompt);
+        if sources.is_empty() {
+            message.push_str("\n(none)");
+        }
+        for (i, source) in sources.iter().enumerate() {
+            let document = &source.document;
+            let name = document
+                .metadata
+                .get("source")
+                .and_then(|s| s.as_str())
+                .unwrap_or(&document.id);
+            message.push_str(&format!("\n\n[{}] ({})\n{}", i + 1, name, document.text));
+        }
+        message
+    }
+}
+
+impl RagAnswer {
+    /// The sources the answer cites with `[n]`, in the order they were given to the model.
+    pub fn cited(&self) -> Vec<&Source> {
+        self.sources
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.answer.contains(&format!("[{}]", i + 1)))
+            .map(|(_, source)| source)
+            .collect()
+    }
+}
+
+impl fmt::Debug for Rag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rag")
+            .field("k", &self.k)
+            .field("min_score", &self.min_score)
+            .field("model", &self.model)
+            .field("embedding_model", &self.embedding_model)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl Retriever for VectorStore {
+    async fn retrieve(&self, _: &str, embedding: &[f32], k: usize) -> Result<Vec<Source>> {
+        Ok(to_sources(self.search(embedding, k, None)))
+    }
+}
+
+/// A `VectorStore` searched only among the documents matching a filter.
+#[derive(Debug, Clone)]
+pub struct FilteredStore {
+    pub store: VectorStore,
+    pub filter: Filter,
+}
+
+#[async_trait]
+impl Retriever for FilteredStore {
+    async fn retrieve(&self, _: &str, embedding: &[f32], k: usize) -> Result<Vec<Source>> {
+        Ok(to_sources(self.store.search(
+            embedding,
+            k,
+            Some(&self.filter),
+        )))
+    }
+}
+
+fn to_sources(results: Vec<SearchResult>) -> Vec<Source> {
+    results
+        .into_iter()
+        .map(|r| Source {
+            document: r.document.clone(),
+            score: r.score,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{MockResponse, MockServer},
+        Distance, Endpoint, TextSplitter,
+    };
+
+    const GUIDE: &str = "# Tea\nGreen tea is healthy and has little caffeine.\n# Cities\nShangHai is a great city by the sea.\n";
+
+    async fn store(sdk: &LLmSdk) -> Result<VectorStore> {
+        let chunks = TextSplitter::markdown(512).split(GUIDE);
+        let documents = chunks.into_iter().map(|c| c.into_document("guide.md"));
+        let mut store = VectorStore::new(Distance::Cosine);
+        store.add(sdk, documents.collect()).await?;
+        Ok(store)
+    }
+
+    #[tokio::test]
+    async fn rag_should_answer_with_cited_sources() -> Result<()> {
+        let server = MockServer::start().await;
+        let sdk = server.sdk();
+        let rag = Rag::new(store(&sdk).await?).with_k(1);
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::chat("ShangHai is by the sea [1]."),
+        );
+        let res = rag.answer(&sdk, "Which city is by the sea?").await?;
+
+        assert_eq!(res.answer, "ShangHai is by the sea [1].");
+        assert_eq!(res.sources.len(), 1);
+        assert!(res.sources[0].document.text.starts_with("# Cities"));
+        assert_eq!(res.cited(), [&res.sources[0]]);
+
+        let body = server.requests().last().unwrap().json().unwrap();
+        let system = body["messages"][0]["content"].as_str().unwrap();
+        assert!(system.starts_with(DEFAULT_PROMPT));
+        assert!(system.contains("[1] (guide.md)\n# Cities"));
+        assert!(!system.contains("Green tea"));
+        assert_eq!(body["messages"][1]["content"], "Which city is by the sea?");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rag_should_filter_sources() -> Result<()> {
+        let server = MockServer::start().await;
+        let sdk = server.sdk();
+        let store = store(&sdk).await?;
+        let rag = Rag::new(FilteredStore {
+            store: store.clone(),
+            filter: Filter::eq("headers", serde_json::json!(["Tea"])),
+        });
+        let (_, sources) = rag.prepare(&sdk, "Which city is by the sea?").await?;
+        assert_eq!(sources.len(), 1);
+        assert!(sources[0].document.text.contains("Green tea"));
+
+        let rag = Rag::new(store).with_min_score(2.0);
+        let (req, sources) = rag.prepare(&sdk, "Which city is by the sea?").await?;
+        assert!(sources.is_empty());
+        let system = serde_json::to_value(&req)?["messages"][0]["content"].clone();
+        assert!(system.as_str().unwrap().ends_with("Sources:\n(none)"));
+        Ok(())
+    }
+}
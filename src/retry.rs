@@ -0,0 +1,246 @@
+use crate::LlmError;
+use derive_builder::Builder;
+use reqwest::{header::HeaderMap, StatusCode};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, SystemTime},
+};
+
+/// How `LLmSdk` retries transient failures: connection errors, timeouts and the statuses in `retryable_statuses`.
+#[derive(Debug, Clone, Builder)]
+#[builder(pattern = "mutable")]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one. 1 disables retries.
+    #[builder(default = "3")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every following retry.
+    #[builder(default = "Duration::from_millis(500)")]
+    pub base_delay: Duration,
+    /// Upper bound of a single delay, also applied to delays asked by the server.
+    #[builder(default = "Duration::from_secs(8)")]
+    pub max_delay: Duration,
+    /// Fraction of the backoff delay that is randomized, between 0 and 1.
+    #[builder(default = "0.25")]
+    pub jitter: f64,
+    /// Response statuses worth retrying.
+    #[builder(default = "RetryPolicy::default_retryable_statuses()", setter(into))]
+    pub retryable_statuses: Vec<StatusCode>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicyBuilder::default().build().unwrap()
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    fn default_retryable_statuses() -> Vec<StatusCode> {
+        vec![
+            StatusCode::REQUEST_TIMEOUT,
+            StatusCode::CONFLICT,
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::BAD_GATEWAY,
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::GATEWAY_TIMEOUT,
+        ]
+    }
+
+    /// Whether `err` is worth another try.
+    pub fn is_retryable(&self, err: &LlmError) -> bool {
+        match err {
+            LlmError::Api(e) => self.retryable_statuses.contains(&e.status),
+            LlmError::Transport(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+            _ => false,
+        }
+    }
+
+    /// The delay before the next attempt, `attempt` being the number of attempts made so far.
+    pub fn delay(&self, attempt: u32, err: &LlmError) -> Duration {
+        if let Some(delay) = err.api_error().and_then(|e| e.retry_after) {
+            return delay.min(self.max_delay);
+        }
+        let exp = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self.base_delay.saturating_mul(exp).min(self.max_delay);
+        let jitter = self.jitter.clamp(0.0, 1.0) * random_fraction();
+        delay.mul_f64(1.0 - jitter)
+    }
+}
+
+/// Read how long the server wants us to wait, from `retry-after-ms`, `retry-after`
+/// or the `x-ratelimit-reset-*` header of the exhausted limit.
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    // Values that don't fit in a `Duration`, like `inf` or `1e400`, are ignored.
+    if let Some(ms) = header("retry-after-ms").and_then(|v| v.parse::<f64>().ok()) {
+        return Duration::try_from_secs_f64(ms.max(0.0) / 1000.0).ok();
+    }
+    if let Some(value) = header("retry-after") {
+        if let Ok(secs) = value.parse::<f64>() {
+            return Duration::try_from_secs_f64(secs.max(0.0)).ok();
+        }
+        if let Ok(date) = httpdate::parse_http_date(value) {
+            return Some(
+                date.duration_since(SystemTime::now())
+                    .unwrap_or(Duration::ZERO),
+            );
+        }
+    }
+
+    ["requests", "tokens"]
+        .iter()
+        .filter(|limit| header(&format!("x-ratelimit-remaining-{}", limit)) == Some("0"))
+        .filter_map(|limit| header(&format!("x-ratelimit-reset-{}", limit)))
+        .filter_map(parse_reset)
+        .max()
+}
+
+/// Parse reset durations like `20ms`, `1s`, `6m0s` or `1h2m3.5s`.
+fn parse_reset(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let num: f64 = rest[..split].parse().ok()?;
+        rest = &rest[split..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let scale = match &rest[..unit_len] {
+            "ms" => 0.001,
+            "s" | "" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return None,
+        };
+        total += num * scale;
+        rest = &rest[unit_len..];
+    }
+    Duration::try_from_secs_f64(total).ok()
+}
+
+/// A random number in [0, 1), good enough for jitter.
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{MockResponse, MockServer},
+        ApiError, ChatCompletionMessage, ChatCompletionRequestBuilder, Endpoint,
+    };
+
+    #[test]
+    fn parse_reset_should_work() {
+        assert_eq!(parse_reset("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(parse_reset("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(
+            parse_reset("1h2m3.5s"),
+            Some(Duration::from_secs_f64(3723.5))
+        );
+        assert_eq!(parse_reset("soon"), None);
+    }
+
+    #[test]
+    fn retry_after_should_read_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert("x-ratelimit-remaining-requests", "10".parse().unwrap());
+        headers.insert("x-ratelimit-reset-requests", "1s".parse().unwrap());
+        headers.insert("x-ratelimit-remaining-tokens", "0".parse().unwrap());
+        headers.insert("x-ratelimit-reset-tokens", "6m0s".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(360)));
+
+        headers.insert("retry-after", "2".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(2)));
+
+        headers.insert("retry-after-ms", "150".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_millis(150)));
+    }
+
+    #[tokio::test]
+    async fn retry_after_should_ignore_overflowing_headers() {
+        for value in ["1e400", "inf"] {
+            let mut headers = HeaderMap::new();
+            headers.insert("retry-after", value.parse().unwrap());
+            assert_eq!(retry_after(&headers), None);
+            let mut headers = HeaderMap::new();
+            headers.insert("retry-after-ms", value.parse().unwrap());
+            assert_eq!(retry_after(&headers), None);
+        }
+        assert_eq!(parse_reset("1e400s"), None);
+        assert_eq!(parse_reset(&format!("{}h", "9".repeat(400))), None);
+
+        let server = MockServer::start().await;
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::error(StatusCode::TOO_MANY_REQUESTS, "Rate limit reached")
+                .with_header("retry-after", "1e400"),
+        );
+        let req = ChatCompletionRequestBuilder::default()
+            .messages(vec![ChatCompletionMessage::new_user("hi", "")])
+            .build()
+            .unwrap();
+        let err = server.sdk().chat_completion(req).await.unwrap_err();
+        assert!(err.is_rate_limited());
+        assert_eq!(err.api_error().unwrap().retry_after, None);
+    }
+
+    #[test]
+    fn retry_policy_delay_should_back_off() {
+        let policy = RetryPolicyBuilder::default()
+            .base_delay(Duration::from_millis(100))
+            .max_delay(Duration::from_secs(1))
+            .jitter(0.0)
+            .build()
+            .unwrap();
+        let err: LlmError =
+            ApiError::new(StatusCode::BAD_GATEWAY, &HeaderMap::new(), "bad gateway").into();
+        assert!(policy.is_retryable(&err));
+        assert_eq!(policy.delay(1, &err), Duration::from_millis(100));
+        assert_eq!(policy.delay(3, &err), Duration::from_millis(400));
+        assert_eq!(policy.delay(10, &err), Duration::from_secs(1));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", "0.5".parse().unwrap());
+        let err: LlmError = ApiError::new(StatusCode::TOO_MANY_REQUESTS, &headers, "").into();
+        assert_eq!(policy.delay(1, &err), Duration::from_millis(500));
+
+        let err: LlmError = ApiError::new(StatusCode::BAD_REQUEST, &headers, "").into();
+        assert!(!policy.is_retryable(&err));
+    }
+
+    #[test]
+    fn retry_policy_jitter_should_stay_in_range() {
+        let policy = RetryPolicyBuilder::default()
+            .base_delay(Duration::from_millis(100))
+            .jitter(0.5)
+            .build()
+            .unwrap();
+        let err: LlmError =
+            ApiError::new(StatusCode::BAD_GATEWAY, &HeaderMap::new(), "bad gateway").into();
+        for _ in 0..100 {
+            let delay = policy.delay(1, &err);
+            assert!(delay > Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use crate::{Document, EmbeddingModel, TokenCounter};
+use derive_builder::Builder;
+use serde_json::Value;
+use std::ops::Range;
+
+/// Splits documents into chunks small enough to embed, at the coarsest boundary that fits:
+/// paragraphs, then lines, sentences, words and finally characters.
+#[derive(Debug, Clone, Builder)]
+#[builder(pattern = "mutable", build_fn(validate = "Self::validate"))]
+pub struct TextSplitter {
+    /// The maximum number of tokens of a chunk.
+    #[builder(default = "512")]
+    chunk_size: usize,
+    /// The number of tokens at the end of a chunk repeated at the start of the next one, so no sentence
+    /// loses its context. Must be smaller than `chunk_size`.
+    #[builder(default = "64")]
+    chunk_overlap: usize,
+    /// Split Markdown documents at their headers first. Chunks never span two sections, and carry the headers above them.
+    #[builder(default)]
+    markdown: bool,
+    /// Counts the tokens of the chunks. Defaults to the tokenizer of the default embedding model.
+    #[builder(default = "TokenCounter::for_model(EmbeddingModel::default())")]
+    counter: TokenCounter,
+}
+
+/// A piece of a document, with where it comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    /// The text of the chunk, without surrounding whitespace.
+    pub text: String,
+    /// The byte range of `text` in the document, `&document[chunk.range] == chunk.text`.
+    pub range: Range<usize>,
+    /// The Markdown headers of the section the chunk is in, outermost first. Empty unless splitting Markdown.
+    pub headers: Vec<String>,
+    /// The number of tokens of `text`.
+    pub tokens: usize,
+}
+
+/// A contiguous piece of the document and its tokens.
+#[derive(Debug, Clone, Copy)]
+struct Piece {
+    start: usize,
+    end: usize,
+    tokens: usize,
+}
+
+/// The boundaries tried in order, from the coarsest.
+const LEVELS: [fn(&str) -> Vec<usize>; 5] = [paragraphs, lines, sentences, words, chars];
+
+impl TextSplitter {
+    /// A splitter of chunks of at most `chunk_size` tokens, overlapping by an eighth of it.
+    /// Panics if `chunk_size` is 0, use `TextSplitterBuilder` to get an error instead.
+    pub fn new(chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk_size must be positive");
+        TextSplitterBuilder::default()
+            .chunk_size(chunk_size)
+            .chunk_overlap(chunk_size / 8)
+            .build()
+            .unwrap()
+    }
+
+    /// A splitter of Markdown documents, see `markdown`. Panics if `chunk_size` is 0.
+    pub fn markdown(chunk_size: usize) -> Self {
+        Self {
+            markdown: true,
+            ..Self::new(chunk_size)
+        }
+    }
+
+    /// Split `text` into chunks of at most `chunk_size` tokens, in order.
+    pub fn split(&self, text: &str) -> Vec<Chunk> {
+        let sections = match self.markdown {
+            true => markdown_sections(text),
+            false => vec![(0..text.len(), vec![])],
+        };
+        let mut chunks = vec![];
+        for (range, headers) in sections {
+            let mut pieces = vec![];
+            self.split_range(text, range, 0, &mut pieces);
+            for piece in self.merge(&pieces) {
+                let slice = &text[piece.start..piece.end];
+                let start = piece.start + (slice.len() - slice.trim_start().len());
+                let end = piece.start + slice.trim_end().len();
+                if start >= end {
+                    continue;
+                }
+                let text = &text[start..end];
+                chunks.push(Chunk {
+                    text: text.to_string(),
+                    range: start..end,
+                    headers: headers.clone(),
+                    tokens: self.counter.count_text(text),
+                });
+            }
+        }
+        chunks
+    }
+
+    /// Split `range` at the boundaries of `level`, and the pieces still too large at the finer levels.
+    fn split_range(&self, text: &str, range: Range<usize>, level: usize, pieces: &mut Vec<Piece>) {
+        let tokens = self.counter.count_text(&text[range.clone()]);
+        if tokens <= self.chunk_size || level == LEVELS.len() {
+            pieces.push(Piece {
+                start: range.start,
+                end: range.end,
+                tokens,
+            });
+            return;
+        }
+        let mut start = range.start;
+        let boundaries = LEVELS[level](&text[range.clone()]);
+        for end in boundaries
+            .into_iter()
+            .map(|b| range.start + b)
+            .chain([range.end])
+        {
+            if end > start {
+                self.split_range(text, start..end, level + 1, pieces);
+                start = end;
+            }
+        }
+    }
+
+    /// Merge consecutive pieces into chunks of at most `chunk_size` tokens, starting every chunk
+    /// with the last pieces of the previous one, up to `chunk_overlap` tokens.
+    fn merge(&self, pieces: &[Piece]) -> Vec<Piece> {
+        let mut chunks = vec![];
+        let mut first = 0;
+        let mut tokens = 0;
+        for (i, piece) in pieces.iter().enumerate() {
+            if i > first && tokens + piece.tokens > self.chunk_size {
+                chunks.push(span(&pieces[first..i], tokens));
+                // Keep the tail of the chunk that fits in the overlap, and leaves room for this piece.
+                let mut overlap = 0;
+                first = i;
+                while first > 0 {
+                    let previous = pieces[first - 1].tokens;
+                    if overlap + previous > self.chunk_overlap
+                        || overlap + previous + piece.tokens > self.chunk_size
+                    {
+                        break;
+                    }
+                    overlap += previous;
+                    first -= 1;
+                }
+                tokens = overlap;
+            }
+            tokens += piece.tokens;
+        }
+        if first < pieces.len() {
+            chunks.push(span(&pieces[first..], tokens));
+        }
+        chunks
+    }
+}
+
+impl TextSplitterBuilder {
+    fn validate(&self) -> Result<(), String> {
+        let size = self.chunk_size.unwrap_or(512);
+        let overlap = self.chunk_overlap.unwrap_or(64);
+        if size == 0 {
+            return Err("chunk_size must be positive".into());
+        }
+        if overlap >= size {
+            return Err(format!(
+                "chunk_overlap must be smaller than chunk_size {}, got {}",
+                size, overlap
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Chunk {
+    /// A document for `VectorStore`, with an id made of `source` and the range of the chunk.
+    /// The metadata has the `source`, `start`, `end` and `headers` of the chunk, to trace it back.
+    pub fn into_document(self, source: &str) -> Document {
+        let id = format!("{}#{}-{}", source, self.range.start, self.range.end);
+        let headers: Vec<Value> = self.headers.into_iter().map(Value::String).collect();
+        Document::new(id, self.text)
+            .with_metadata("source", source)
+            .with_metadata("start", self.range.start)
+            .with_metadata("end", self.range.end)
+            .with_metadata("headers", headers)
+    }
+}
+
+fn span(pieces: &[Piece], tokens: usize) -> Piece {
+    Piece {
+        start: pieces[0].start,
+        end: pieces[pieces.len() - 1].end,
+        tokens,
+    }
+}
+
+/// The Markdown sections of `text`, each starting at its header, with the headers above it.
+/// Lines starting with `#` in fenced code blocks are not headers.
+fn markdown_sections(text: &str) -> Vec<(Range<usize>, Vec<String>)> {
+    let mut sections = vec![];
+    let mut stack: Vec<(usize, String)> = vec![];
+    let mut start = 0;
+    let mut in_code = false;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code = !in_code;
+        }
+        let level = trimmed.chars().take_while(|&c| c == '#').count();
+        let is_header = !in_code
+            && (1..=6).contains(&level)
+            && trimmed[level..].starts_with([' ', '\t'])
+            && line.starts_with('#');
+        if is_header {
+            if offset > start {
+                sections.push((start..offset, titles(&stack)));
+            }
+            while stack.last().is_some_and(|(l, _)| *l >= level) {
+                stack.pop();
+            }
+            stack.push((level, trimmed[level..].trim().to_string()));
+            start = offset;
+        }
+        offset += line.len();
+    }
+    if offset > start {
+        sections.push((start..offset, titles(&stack)));
+    }
+    sections
+}
+
+fn titles(stack: &[(usize, String)]) -> Vec<String> {
+    stack.iter().map(|(_, title)| title.clone()).collect()
+}
+
+/// After every run of blank lines.
+fn paragraphs(text: &str) -> Vec<usize> {
+    let mut boundaries = vec![];
+    let mut newlines = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\n' => newlines += 1,
+            c if c.is_whitespace() => {}
+            _ => {
+                if newlines >= 2 {
+                    boundaries.push(i);
+                }
+                newlines = 0;
+            }
+        }
+    }
+    boundaries
+}
+
+/// After every line break.
+fn lines(text: &str) -> Vec<usize> {
+    text.match_indices('\n').map(|(i, _)| i + 1).collect()
+}
+
+/// After the punctuation ending a sentence, when followed by whitespace. Always after CJK full stops.
+fn sentences(text: &str) -> Vec<usize> {
+    let mut boundaries = vec![];
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let end = i + c.len_utf8();
+        let next = chars.peek().map(|&(_, c)| c);
+        match c {
+            '。' | '！' | '？' => boundaries.push(end),
+            '.' | '!' | '?' if next.is_some_and(char::is_whitespace) => boundaries.push(end),
+            _ => {}
+        }
+    }
+    boundaries
+}
+
+/// Before every word.
+fn words(text: &str) -> Vec<usize> {
+    let mut boundaries = vec![];
+    let mut previous = None;
+    for (i, c) in text.char_indices() {
+        if previous.is_some_and(char::is_whitespace) && !c.is_whitespace() {
+            boundaries.push(i);
+        }
+        previous = Some(c);
+    }
+    boundaries
+}
+
+fn chars(text: &str) -> Vec<usize> {
+    text.char_indices().skip(1).map(|(i, _)| i).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CharEstimate;
+
+    /// 4 characters per token, to have exact sizes in tests.
+    fn splitter(chunk_size: usize, chunk_overlap: usize) -> TextSplitterBuilder {
+        let mut builder = TextSplitterBuilder::default();
+        builder
+            .chunk_size(chunk_size)
+            .chunk_overlap(chunk_overlap)
+            .counter(TokenCounter::new(CharEstimate));
+        builder
+    }
+
+    fn check(text: &str, chunks: &[Chunk], chunk_size: usize) {
+        for chunk in chunks {
+            assert_eq!(&text[chunk.range.clone()], chunk.text);
+            assert!(chunk.tokens <= chunk_size, "{:?}", chunk);
+        }
+    }
+
+    #[test]
+    fn split_should_prefer_paragraphs_and_sentences() {
+        let text = "First paragraph is short.\n\nSecond paragraph has two sentences. It is longer than the first one.\n\nThird.";
+        let chunks = splitter(12, 0).build().unwrap().split(text);
+        check(text, &chunks, 12);
+        let texts: Vec<_> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            [
+                "First paragraph is short.",
+                "Second paragraph has two sentences.",
+                "It is longer than the first one.",
+                "Third."
+            ]
+        );
+
+        let chunks = TextSplitter::new(512).split(text);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].range, 0..text.len());
+        assert!(TextSplitter::new(512).split(" \n\n ").is_empty());
+    }
+
+    #[test]
+    fn split_should_overlap_and_split_long_words() {
+        let text = "one two three four five six seven eight nine ten eleven twelve";
+        let chunks = splitter(5, 2).build().unwrap().split(text);
+        check(text, &chunks, 5);
+        assert!(chunks.len() > 2);
+        for pair in chunks.windows(2) {
+            assert!(pair[1].range.start < pair[0].range.end, "{:?}", pair);
+            assert!(pair[1].range.end > pair[0].range.end);
+        }
+
+        let text = "a".repeat(30) + "。中文句子。";
+        let chunks = splitter(3, 0).build().unwrap().split(&text);
+        check(&text, &chunks, 3);
+        assert_eq!(
+            chunks.iter().map(|c| c.text.as_str()).collect::<String>(),
+            text
+        );
+
+        assert!(splitter(4, 4).build().is_err());
+        assert!(splitter(0, 0).build().is_err());
+    }
+
+    #[test]
+    fn split_should_follow_markdown_headers() {
+        let text = "Intro.\n# Guide\nSetup text.\n## Install\nRun it.\n```sh\n# not a header\n```\n# FAQ\nAsk.\n";
+        let chunks = TextSplitter::markdown(512).split(text);
+        check(text, &chunks, 512);
+        let sections: Vec<_> = chunks
+            .iter()
+            .map(|c| (c.text.lines().next().unwrap(), c.headers.join(" > ")))
+            .collect();
+        assert_eq!(
+            sections,
+            [
+                ("Intro.", "".to_string()),
+                ("# Guide", "Guide".to_string()),
+                ("## Install", "Guide > Install".to_string()),
+                ("# FAQ", "FAQ".to_string()),
+            ]
+        );
+        assert!(chunks[2].text.contains("# not a header"));
+
+        let document = chunks[2].clone().into_document("guide.md");
+        assert_eq!(
+            document.id,
+            format!("guide.md#{}-{}", chunks[2].range.start, chunks[2].range.end)
+        );
+        assert_eq!(
+            document.metadata["headers"],
+            serde_json::json!(["Guide", "Install"])
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "chunk_size must be positive")]
+    fn new_should_reject_empty_chunks() {
+        TextSplitter::markdown(0);
+    }
+
+    #[test]
+    fn builder_should_reject_empty_chunks() {
+        let err = splitter(0, 0).build().unwrap_err();
+        assert!(err.to_string().contains("chunk_size must be positive"));
+    }
+}
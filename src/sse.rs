@@ -0,0 +1,94 @@
+use crate::Result;
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
+use std::collections::VecDeque;
+
+/// Incremental decoder for `text/event-stream` bodies. The API only sends `data` fields, so everything else is dropped.
+#[derive(Debug, Default)]
+pub(crate) struct SseDecoder {
+    buf: Vec<u8>,
+    data: Vec<String>,
+}
+
+impl SseDecoder {
+    /// Feed raw bytes and get back the data of every event completed by them.
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buf.extend_from_slice(chunk);
+        let mut events = vec![];
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Some(event) = self.process_line(line.trim_end_matches(['\r', '\n'])) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    /// Flush the last event if the body didn't end with a blank line.
+    pub(crate) fn finish(&mut self) -> Option<String> {
+        let rest = std::mem::take(&mut self.buf);
+        let rest = String::from_utf8_lossy(&rest);
+        let event = self.process_line(rest.trim_end_matches(['\r', '\n']));
+        event.or_else(|| self.process_line(""))
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<String> {
+        if line.is_empty() {
+            return (!self.data.is_empty()).then(|| std::mem::take(&mut self.data).join("\n"));
+        }
+        if let Some(data) = line.strip_prefix("data:") {
+            self.data
+                .push(data.strip_prefix(' ').unwrap_or(data).to_string());
+        }
+        None
+    }
+}
+
+/// Turn a response body into a stream of event data.
+pub(crate) fn events<S>(body: S) -> impl Stream<Item = Result<String>>
+where
+    S: Stream<Item = reqwest::Result<Bytes>> + Unpin,
+{
+    let state = (body, SseDecoder::default(), VecDeque::new(), false);
+    stream::unfold(
+        state,
+        |(mut body, mut decoder, mut pending, mut done)| async move {
+            loop {
+                if let Some(event) = pending.pop_front() {
+                    return Some((Ok(event), (body, decoder, pending, done)));
+                }
+                if done {
+                    return None;
+                }
+                match body.next().await {
+                    Some(Ok(bytes)) => pending.extend(decoder.push(&bytes)),
+                    Some(Err(e)) => {
+                        return Some((Err(e.into()), (body, decoder, pending, true)));
+                    }
+                    None => {
+                        pending.extend(decoder.finish());
+                        done = true;
+                    }
+                }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sse_decoder_should_work() {
+        let mut decoder = SseDecoder::default();
+        assert!(decoder.push(b"data: {\"a\":").is_empty());
+        assert_eq!(
+            decoder.push(b"1}\n\n: keep-alive\n\ndata: [DONE]\r\n\r\n"),
+            vec!["{\"a\":1}".to_string(), "[DONE]".to_string()]
+        );
+        assert_eq!(decoder.push(b"data: a\ndata: b"), Vec::<String>::new());
+        assert_eq!(decoder.finish(), Some("a\nb".to_string()));
+    }
+}
@@ -0,0 +1,370 @@
+use crate::{ChatCompletionMessage, LlmError, Result};
+use async_trait::async_trait;
+use std::{io, path::PathBuf};
+
+/// Where conversations are saved, so they survive process restarts. See `Conversation::save` and `Conversation::load`.
+#[async_trait]
+pub trait ConversationStore: Send + Sync {
+    /// Save the messages of the conversation `id`, replacing what was saved before.
+    async fn save(&self, id: &str, messages: &[ChatCompletionMessage]) -> Result<()>;
+    /// The messages of the conversation `id`, `None` if it was never saved.
+    async fn load(&self, id: &str) -> Result<Option<Vec<ChatCompletionMessage>>>;
+    /// Forget the conversation `id`. Deleting a conversation that doesn't exist is not an error.
+    async fn delete(&self, id: &str) -> Result<()>;
+    /// The ids of the saved conversations, sorted.
+    async fn list(&self) -> Result<Vec<String>>;
+}
+
+/// Saves every conversation in its own `<id>.jsonl` file, one message per line.
+#[derive(Debug, Clone)]
+pub struct JsonlStore {
+    dir: PathBuf,
+}
+
+impl JsonlStore {
+    /// Store the conversations in `dir`, which is created on the first save.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, id: &str) -> Result<PathBuf> {
+        let valid = !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(LlmError::Storage(format!(
+                "invalid conversation id {:?}, use letters, digits, - and _",
+                id
+            )));
+        }
+        Ok(self.dir.join(format!("{}.jsonl", id)))
+    }
+}
+
+#[async_trait]
+impl ConversationStore for JsonlStore {
+    async fn save(&self, id: &str, messages: &[ChatCompletionMessage]) -> Result<()> {
+        let path = self.path(id)?;
+        let mut data = String::new();
+        for message in messages {
+            data.push_str(&serde_json::to_string(message)?);
+            data.push('\n');
+        }
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(io_error)?;
+        // write then rename, to never leave a half written conversation
+        let tmp = path.with_extension("jsonl.tmp");
+        tokio::fs::write(&tmp, data).await.map_err(io_error)?;
+        tokio::fs::rename(&tmp, &path).await.map_err(io_error)
+    }
+
+    async fn load(&self, id: &str) -> Result<Option<Vec<ChatCompletionMessage>>> {
+        let data = match tokio::fs::read_to_string(self.path(id)?).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(io_error(e)),
+        };
+        let messages = data
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        Ok(Some(messages))
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.path(id)?).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(io_error(e)),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(io_error(e)),
+        };
+        let mut ids = vec![];
+        while let Some(entry) = entries.next_entry().await.map_err(io_error)? {
+            let name = entry.file_name();
+            if let Some(id) = name.to_str().and_then(|n| n.strip_suffix(".jsonl")) {
+                ids.push(id.to_string());
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+}
+
+fn io_error(e: io::Error) -> LlmError {
+    LlmError::Storage(e.to_string())
+}
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::*;
+    use rusqlite::{params, Connection, OptionalExtension};
+    use std::{
+        path::Path,
+        sync::{Arc, Mutex},
+    };
+
+    /// Saves the conversations in an SQLite database, one row per message. Needs the `sqlite` feature.
+    #[derive(Debug, Clone)]
+    pub struct SqliteStore {
+        conn: Arc<Mutex<Connection>>,
+    }
+
+    impl SqliteStore {
+        /// Open or create the database at `path`.
+        pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+            Self::init(Connection::open(path).map_err(sqlite_error)?)
+        }
+
+        /// A database living in memory, lost when the store is dropped.
+        pub fn in_memory() -> Result<Self> {
+            Self::init(Connection::open_in_memory().map_err(sqlite_error)?)
+        }
+
+        fn init(conn: Connection) -> Result<Self> {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS conversations (
+                    id TEXT PRIMARY KEY
+                );
+                CREATE TABLE IF NOT EXISTS messages (
+                    conversation_id TEXT NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+                    position INTEGER NOT NULL,
+                    message TEXT NOT NULL,
+                    PRIMARY KEY (conversation_id, position)
+                );
+                PRAGMA foreign_keys = ON;",
+            )
+            .map_err(sqlite_error)?;
+            Ok(Self {
+                conn: Arc::new(Mutex::new(conn)),
+            })
+        }
+
+        /// Run `f` with the connection, off the async runtime.
+        async fn with_conn<T, F>(&self, f: F) -> Result<T>
+        where
+            T: Send + 'static,
+            F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+        {
+            let conn = self.conn.clone();
+            tokio::task::spawn_blocking(move || {
+                let mut conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+                f(&mut conn).map_err(sqlite_error)
+            })
+            .await
+            .map_err(|e| LlmError::Storage(e.to_string()))?
+        }
+    }
+
+    #[async_trait]
+    impl ConversationStore for SqliteStore {
+        async fn save(&self, id: &str, messages: &[ChatCompletionMessage]) -> Result<()> {
+            let id = id.to_string();
+            let messages = messages
+                .iter()
+                .map(serde_json::to_string)
+                .collect::<Result<Vec<_>, _>>()?;
+            self.with_conn(move |conn| {
+                let tx = conn.transaction()?;
+                tx.execute("DELETE FROM conversations WHERE id = ?1", params![id])?;
+                tx.execute("INSERT INTO conversations (id) VALUES (?1)", params![id])?;
+                for (position, message) in messages.iter().enumerate() {
+                    tx.execute(
+                        "INSERT INTO messages (conversation_id, position, message) VALUES (?1, ?2, ?3)",
+                        params![id, position, message],
+                    )?;
+                }
+                tx.commit()
+            })
+            .await
+        }
+
+        async fn load(&self, id: &str) -> Result<Option<Vec<ChatCompletionMessage>>> {
+            let id = id.to_string();
+            let rows = self
+                .with_conn(move |conn| {
+                    let exists = conn
+                        .query_row(
+                            "SELECT 1 FROM conversations WHERE id = ?1",
+                            params![id],
+                            |_| Ok(()),
+                        )
+                        .optional()?;
+                    if exists.is_none() {
+                        return Ok(None);
+                    }
+                    let mut stmt = conn.prepare(
+                        "SELECT message FROM messages WHERE conversation_id = ?1 ORDER BY position",
+                    )?;
+                    let rows = stmt
+                        .query_map(params![id], |row| row.get::<_, String>(0))?
+                        .collect::<rusqlite::Result<Vec<_>>>()?;
+                    Ok(Some(rows))
+                })
+                .await?;
+            rows.map(|rows| {
+                rows.iter()
+                    .map(|row| Ok(serde_json::from_str(row)?))
+                    .collect()
+            })
+            .transpose()
+        }
+
+        async fn delete(&self, id: &str) -> Result<()> {
+            let id = id.to_string();
+            self.with_conn(move |conn| {
+                conn.execute("DELETE FROM conversations WHERE id = ?1", params![id])
+                    .map(|_| ())
+            })
+            .await
+        }
+
+        async fn list(&self) -> Result<Vec<String>> {
+            self.with_conn(|conn| {
+                let mut stmt = conn.prepare("SELECT id FROM conversations ORDER BY id")?;
+                let ids = stmt
+                    .query_map([], |row| row.get(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok(ids)
+            })
+            .await
+        }
+    }
+
+    fn sqlite_error(e: rusqlite::Error) -> LlmError {
+        LlmError::Storage(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChatCompletionRequestBuilder, ContentPart, Conversation, ImageDetail};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn messages() -> Vec<ChatCompletionMessage> {
+        serde_json::from_value(serde_json::json!([
+            { "role": "system", "content": "You are a helpful assistant." },
+            { "role": "user", "content": "What is the weather like in ShangHai?", "name": "tyr" },
+            {
+                "role": "assistant",
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "get_weather_forecast", "arguments": "{\"city\":\"ShangHai\"}" }
+                }]
+            },
+            { "role": "tool", "content": "{\"temperature\":22.1}", "tool_call_id": "call_1" },
+            { "role": "assistant", "content": "It's 22.1 degrees." },
+        ]))
+        .unwrap()
+    }
+
+    async fn store_should_round_trip(store: &dyn ConversationStore) -> Result<()> {
+        assert_eq!(store.load("chat-1").await?, None);
+        assert!(store.list().await?.is_empty());
+
+        store.save("chat-1", &messages()).await?;
+        store.save("chat-2", &messages()[..2]).await?;
+        store.save("chat-2", &messages()[..1]).await?;
+        assert_eq!(store.load("chat-1").await?, Some(messages()));
+        assert_eq!(store.load("chat-2").await?, Some(messages()[..1].to_vec()));
+        assert_eq!(store.list().await?, ["chat-1", "chat-2"]);
+
+        store.delete("chat-1").await?;
+        store.delete("chat-1").await?;
+        assert_eq!(store.load("chat-1").await?, None);
+        assert_eq!(store.list().await?, ["chat-2"]);
+        Ok(())
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir().join(format!("q-bot-{}-{}-{}", std::process::id(), nanos, name))
+    }
+
+    #[test]
+    fn messages_should_deserialize() {
+        let message = ChatCompletionMessage::new_user(
+            vec![
+                ContentPart::text("What's in this image?"),
+                ContentPart::image_url("https://example.com/a.png", Some(ImageDetail::Low)),
+            ],
+            "",
+        );
+        let json = serde_json::to_string(&message).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ChatCompletionMessage>(&json).unwrap(),
+            message
+        );
+
+        let json = serde_json::to_string(&messages()).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<ChatCompletionMessage>>(&json).unwrap(),
+            messages()
+        );
+    }
+
+    #[tokio::test]
+    async fn jsonl_store_should_work() -> Result<()> {
+        let dir = temp_dir("jsonl");
+        let store = JsonlStore::new(&dir);
+        store_should_round_trip(&store).await?;
+
+        let data = std::fs::read_to_string(dir.join("chat-2.jsonl")).unwrap();
+        assert_eq!(data.lines().count(), 1);
+        assert!(matches!(
+            store.save("../chat", &messages()).await,
+            Err(LlmError::Storage(_))
+        ));
+        let _ = std::fs::remove_dir_all(dir);
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn sqlite_store_should_work() -> Result<()> {
+        store_should_round_trip(&SqliteStore::in_memory()?).await
+    }
+
+    #[tokio::test]
+    async fn conversation_should_save_and_load() -> Result<()> {
+        let dir = temp_dir("conversation");
+        let store = JsonlStore::new(&dir);
+        let mut conversation = Conversation::new("You are a helpful assistant.");
+        for message in messages().into_iter().skip(1) {
+            conversation.push(message);
+        }
+        conversation.save(&store, "chat-1").await?;
+
+        let request = ChatCompletionRequestBuilder::default()
+            .messages(vec![])
+            .model("gpt-4o")
+            .build()
+            .unwrap();
+        let loaded = Conversation::load(&store, "chat-1", request.clone())
+            .await?
+            .unwrap();
+        assert_eq!(loaded.messages(), messages());
+        assert_eq!(loaded.turns(), 1);
+        assert!(Conversation::load(&store, "chat-2", request)
+            .await?
+            .is_none());
+        let _ = std::fs::remove_dir_all(dir);
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use crate::{AssistantMessage, LlmError, Result};
+use jsonschema::JSONSchema;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+/// Validates replies against the json schema they were asked to match.
+pub(crate) struct StructuredOutput {
+    schema: JSONSchema,
+}
+
+impl StructuredOutput {
+    pub(crate) fn new(schema: &Value) -> Result<Self> {
+        let schema = JSONSchema::compile(schema)
+            .map_err(|e| LlmError::Validation(format!("invalid schema: {}", e)))?;
+        Ok(Self { schema })
+    }
+
+    /// Parse the reply, validate it against the schema and deserialize it.
+    pub(crate) fn parse<T: DeserializeOwned>(&self, message: &AssistantMessage) -> Result<T> {
+        if let Some(refusal) = message.refusal() {
+            return Err(LlmError::Validation(format!(
+                "the model refused: {}",
+                refusal
+            )));
+        }
+        let content = message
+            .content()
+            .ok_or_else(|| LlmError::Validation("the reply is empty".into()))?;
+        let value: Value = serde_json::from_str(content)?;
+        if let Err(errors) = self.schema.validate(&value) {
+            let errors: Vec<_> = errors
+                .map(|e| match e.instance_path.to_string() {
+                    path if path.is_empty() => e.to_string(),
+                    path => format!("{}: {}", path, e),
+                })
+                .collect();
+            return Err(LlmError::Validation(errors.join("; ")));
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ToSchema;
+    use schemars::JsonSchema;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, JsonSchema, Deserialize)]
+    struct Weather {
+        city: String,
+        temperature: f32,
+    }
+
+    fn message(content: &str) -> AssistantMessage {
+        serde_json::from_value(serde_json::json!({ "content": content })).unwrap()
+    }
+
+    #[test]
+    fn structured_output_should_parse_valid_reply() -> anyhow::Result<()> {
+        let output = StructuredOutput::new(&Weather::to_schema())?;
+        let weather: Weather =
+            output.parse(&message(r#"{"city":"ShangHai","temperature":22.5}"#))?;
+        assert_eq!(
+            weather,
+            Weather {
+                city: "ShangHai".into(),
+                temperature: 22.5
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn structured_output_should_reject_invalid_reply() -> anyhow::Result<()> {
+        let output = StructuredOutput::new(&Weather::to_schema())?;
+
+        let err = output
+            .parse::<Weather>(&message(r#"{"city":"ShangHai","temperature":"hot"}"#))
+            .unwrap_err();
+        assert!(matches!(&err, LlmError::Validation(e) if e.starts_with("/temperature")));
+
+        let err = output.parse::<Weather>(&message("ShangHai")).unwrap_err();
+        assert!(matches!(err, LlmError::Decode(_)));
+
+        let refusal = serde_json::from_value(serde_json::json!({ "refusal": "no" }))?;
+        let err = output.parse::<Weather>(&refusal).unwrap_err();
+        assert!(matches!(err, LlmError::Validation(_)));
+        Ok(())
+    }
+}
@@ -0,0 +1,503 @@
+//! An in-process mock of the OpenAI API, to test code using `LLmSdk` without network.
+//!
+//! Every endpoint answers with a deterministic default response, unless responses were queued for it
+//! with `MockServer::enqueue`. All requests are captured and can be inspected with `MockServer::requests`.
+
+use crate::{Endpoint, LLmSdk, RetryPolicy};
+use bytes::Bytes;
+use futures::channel::oneshot;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Server,
+};
+use reqwest::{header::HeaderMap, Method, StatusCode};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+/// The reply of the default transcription.
+pub const MOCK_TRANSCRIPTION: &str = "This is a mock transcription.";
+/// The size of the default embeddings.
+pub const MOCK_EMBEDDING_SIZE: usize = 16;
+
+/// A mock OpenAI server listening on a random local port. It stops when dropped.
+#[derive(Debug)]
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    queued: HashMap<Endpoint, VecDeque<MockResponse>>,
+    requests: Vec<CapturedRequest>,
+}
+
+/// A scripted response of the mock server.
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    body: Bytes,
+}
+
+/// A request received by the mock server.
+#[derive(Debug, Clone)]
+pub struct CapturedRequest {
+    pub method: Method,
+    /// The path of the request, without the `/v1` prefix.
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl MockServer {
+    /// Start the server on the current tokio runtime.
+    ///
+    /// # Panics
+    /// If no local port can be bound.
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        let (shutdown, rx) = oneshot::channel();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            rx.await.ok();
+        }));
+        Self {
+            addr,
+            state,
+            shutdown: Some(shutdown),
+        }
+    }
+
+    /// The base url to give to `LLmSdk`.
+    pub fn url(&self) -> String {
+        format!("http://{}/v1", self.addr)
+    }
+
+    /// An `LLmSdk` pointed at the server. It doesn't retry, so every queued response is seen by one call.
+    pub fn sdk(&self) -> LLmSdk {
+        LLmSdk::new(self.url(), "sk-mock").with_retry_policy(RetryPolicy::none())
+    }
+
+    /// Answer the next request to `endpoint` with `response`. Queued responses are used in order,
+    /// then the endpoint falls back to its default response.
+    pub fn enqueue(&self, endpoint: Endpoint, response: MockResponse) {
+        self.state
+            .lock()
+            .unwrap()
+            .queued
+            .entry(endpoint)
+            .or_default()
+            .push_back(response);
+    }
+
+    /// The requests received so far, oldest first.
+    pub fn requests(&self) -> Vec<CapturedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+    }
+}
+
+impl MockResponse {
+    pub fn new(status: StatusCode, body: impl Into<Bytes>) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: body.into(),
+        }
+    }
+
+    pub fn json(value: Value) -> Self {
+        Self::new(StatusCode::OK, value.to_string()).with_header("content-type", "application/json")
+    }
+
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::new(StatusCode::OK, text.into()).with_header("content-type", "text/plain")
+    }
+
+    /// A chat completion answering `content`.
+    pub fn chat(content: impl Into<String>) -> Self {
+        Self::json(chat_completion(
+            "gpt-3.5-turbo",
+            json!({ "role": "assistant", "content": content.into() }),
+            "stop",
+        ))
+    }
+
+    /// A chat completion calling the function `name` with `arguments`.
+    pub fn tool_call(name: &str, arguments: Value) -> Self {
+        let call = json!({
+            "id": format!("call_{}", name),
+            "type": "function",
+            "function": { "name": name, "arguments": arguments.to_string() },
+        });
+        Self::json(chat_completion(
+            "gpt-3.5-turbo",
+            json!({ "role": "assistant", "tool_calls": [call] }),
+            "tool_calls",
+        ))
+    }
+
+    /// A streamed chat completion, with one chunk for each of `pieces`.
+    pub fn chat_stream(pieces: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self::events(chat_chunks(
+            "gpt-3.5-turbo",
+            pieces.into_iter().map(Into::into),
+        ))
+    }
+
+    /// Server-sent events with `events` as data, ended by `[DONE]`.
+    pub fn events(events: impl IntoIterator<Item = Value>) -> Self {
+        let mut body: String = events
+            .into_iter()
+            .map(|event| format!("data: {}\n\n", event))
+            .collect();
+        body.push_str("data: [DONE]\n\n");
+        Self::new(StatusCode::OK, body).with_header("content-type", "text/event-stream")
+    }
+
+    /// An embedding response with `embeddings`, in order.
+    pub fn embeddings(embeddings: impl IntoIterator<Item = Vec<f32>>) -> Self {
+        Self::json(embedding_response(embeddings.into_iter().map(|e| json!(e))))
+    }
+
+    /// An OpenAI error object with `message`.
+    pub fn error(status: StatusCode, message: &str) -> Self {
+        let code = match status {
+            StatusCode::TOO_MANY_REQUESTS => "rate_limit_exceeded",
+            StatusCode::UNAUTHORIZED => "invalid_api_key",
+            StatusCode::NOT_FOUND => "not_found",
+            s if s.is_server_error() => "server_error",
+            _ => "invalid_request_error",
+        };
+        let body = json!({
+            "error": { "message": message, "type": code, "param": null, "code": code },
+        });
+        Self::new(status, body.to_string()).with_header("content-type", "application/json")
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    fn into_response(self) -> hyper::Response<Body> {
+        let mut res = hyper::Response::builder().status(self.status);
+        for (name, value) in &self.headers {
+            res = res.header(name, value);
+        }
+        res.body(Body::from(self.body)).unwrap()
+    }
+}
+
+impl CapturedRequest {
+    /// The body parsed as json, if it is json.
+    pub fn json(&self) -> Option<Value> {
+        serde_json::from_slice(&self.body).ok()
+    }
+
+    /// The endpoint the request was sent to.
+    pub fn endpoint(&self) -> Option<Endpoint> {
+        match self.path.as_str() {
+            "/chat/completions" => Some(Endpoint::ChatCompletions),
+            "/embeddings" => Some(Endpoint::Embeddings),
+            "/images/generations" => Some(Endpoint::Images),
+            "/audio/speech" => Some(Endpoint::Speech),
+            "/audio/transcriptions" | "/audio/translations" => Some(Endpoint::Whisper),
+            _ => None,
+        }
+    }
+
+    /// The value of the multipart text field `name`, for audio requests.
+    pub fn form_field(&self, name: &str) -> Option<String> {
+        let body = String::from_utf8_lossy(&self.body);
+        let start = body.find(&format!("name=\"{}\"\r\n\r\n", name))?;
+        let value = &body[start + name.len() + 11..];
+        Some(value[..value.find("\r\n")?].to_string())
+    }
+}
+
+async fn handle(
+    state: Arc<Mutex<State>>,
+    req: hyper::Request<Body>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+    let path = parts.uri.path();
+    let req = CapturedRequest {
+        method: parts.method,
+        path: path.strip_prefix("/v1").unwrap_or(path).to_string(),
+        headers: parts.headers,
+        body,
+    };
+
+    let response = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(req.clone());
+        req.endpoint()
+            .and_then(|endpoint| state.queued.get_mut(&endpoint)?.pop_front())
+    };
+    let response = response.unwrap_or_else(|| default_response(&req));
+    Ok(response.into_response())
+}
+
+fn default_response(req: &CapturedRequest) -> MockResponse {
+    let body = req.json().unwrap_or_default();
+    match req.endpoint() {
+        Some(Endpoint::ChatCompletions) => {
+            let model = body["model"].as_str().unwrap_or("gpt-3.5-turbo");
+            let content = format!("Mock reply to: {}", last_user_message(&body));
+            match body["stream"].as_bool() {
+                Some(true) => MockResponse::events(chat_chunks(
+                    model,
+                    content.split_inclusive(' ').map(Into::into),
+                )),
+                _ => MockResponse::json(chat_completion(
+                    model,
+                    json!({ "role": "assistant", "content": content }),
+                    "stop",
+                )),
+            }
+        }
+        Some(Endpoint::Embeddings) => {
+            let inputs = match &body["input"] {
+                Value::Array(inputs) => inputs.iter().filter_map(Value::as_str).collect(),
+                input => vec![input.as_str().unwrap_or_default()],
+            };
+            let dimensions = body["dimensions"].as_u64().map(|d| d as usize);
+            let embeddings = inputs.into_iter().map(|input| {
+                let embedding = mock_embedding(input);
+                let embedding = match dimensions {
+                    Some(dimensions) => shorten(embedding, dimensions),
+                    None => embedding,
+                };
+                match body["encoding_format"].as_str() {
+                    Some("base64") => json!(crate::encode_f32s(&embedding)),
+                    _ => json!(embedding),
+                }
+            });
+            MockResponse::json(embedding_response(embeddings))
+        }
+        Some(Endpoint::Images) => {
+            let image = match body["response_format"].as_str() {
+                Some("b64_json") => json!({ "b64_json": "bW9jayBpbWFnZQ==" }),
+                _ => json!({ "url": "https://example.com/mock.png" }),
+            };
+            let mut image = image.as_object().unwrap().clone();
+            image.insert("revised_prompt".into(), body["prompt"].clone());
+            MockResponse::json(json!({ "created": 0, "data": [image] }))
+        }
+        Some(Endpoint::Speech) => MockResponse::new(StatusCode::OK, "mock audio")
+            .with_header("content-type", "audio/mpeg"),
+        Some(Endpoint::Whisper) => match req.form_field("response_format").as_deref() {
+            Some("json") | Some("verbose_json") | None => {
+                MockResponse::json(json!({ "text": MOCK_TRANSCRIPTION }))
+            }
+            Some(_) => MockResponse::text(MOCK_TRANSCRIPTION),
+        },
+        None => MockResponse::error(
+            StatusCode::NOT_FOUND,
+            &format!("Invalid URL ({} {})", req.method, req.path),
+        ),
+    }
+}
+
+/// A deterministic embedding of `text`: its words hashed into buckets and normalized,
+/// so texts sharing words are similar.
+pub fn mock_embedding(text: &str) -> Vec<f32> {
+    let mut embedding = vec![0.0; MOCK_EMBEDDING_SIZE];
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+    {
+        let hash = word
+            .to_lowercase()
+            .bytes()
+            .fold(0usize, |h, b| h.wrapping_mul(31).wrapping_add(b as usize));
+        embedding[hash % MOCK_EMBEDDING_SIZE] += 1.0;
+    }
+    normalize(&mut embedding);
+    embedding
+}
+
+/// A path in the temp directory, unique to the call, ending with `name`. Nothing is created.
+#[cfg(test)]
+pub(crate) fn temp_path(name: &str) -> std::path::PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("q-bot-{}-{}-{}", std::process::id(), count, name))
+}
+
+/// Keep the first `dimensions` of `embedding` and normalize it again, like the API does.
+fn shorten(mut embedding: Vec<f32>, dimensions: usize) -> Vec<f32> {
+    embedding.truncate(dimensions);
+    normalize(&mut embedding);
+    embedding
+}
+
+fn normalize(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+fn last_user_message(body: &Value) -> String {
+    let message = body["messages"]
+        .as_array()
+        .and_then(|messages| messages.iter().rev().find(|m| m["role"] == "user"));
+    match message.map(|m| &m["content"]) {
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Array(parts)) => parts
+            .iter()
+            .filter_map(|p| p["text"].as_str())
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ => String::new(),
+    }
+}
+
+fn chat_completion(model: &str, message: Value, finish_reason: &str) -> Value {
+    json!({
+        "id": "chatcmpl-mock",
+        "object": "chat.completion",
+        "created": 0,
+        "model": model,
+        "system_fingerprint": "fp_mock",
+        "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason }],
+        "usage": { "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15 },
+    })
+}
+
+fn chat_chunks(model: &str, pieces: impl Iterator<Item = String>) -> Vec<Value> {
+    let chunk = |delta: Value, finish_reason: Option<&str>| {
+        json!({
+            "id": "chatcmpl-mock",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": model,
+            "system_fingerprint": "fp_mock",
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        })
+    };
+    let mut chunks = vec![chunk(json!({ "role": "assistant", "content": "" }), None)];
+    chunks.extend(pieces.map(|piece| chunk(json!({ "content": piece }), None)));
+    chunks.push(chunk(json!({}), Some("stop")));
+    chunks
+}
+
+fn embedding_response(embeddings: impl IntoIterator<Item = Value>) -> Value {
+    let data: Vec<_> = embeddings
+        .into_iter()
+        .enumerate()
+        .map(|(index, embedding)| {
+            json!({ "object": "embedding", "index": index, "embedding": embedding })
+        })
+        .collect();
+    json!({
+        "object": "list",
+        "data": data,
+        "model": "text-embedding-ada-002",
+        "usage": { "prompt_tokens": 8, "total_tokens": 8 },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChatCompletionMessage, ChatCompletionRequestBuilder, EmbeddingRequest, Result};
+    use std::time::Duration;
+
+    fn chat_request() -> crate::ChatCompletionRequest {
+        ChatCompletionRequestBuilder::default()
+            .messages(vec![ChatCompletionMessage::new_user("Hello!", "")])
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn mock_server_should_serve_default_responses() -> Result<()> {
+        let server = MockServer::start().await;
+        let sdk = server.sdk();
+
+        let res = sdk.chat_completion(chat_request()).await?;
+        assert_eq!(
+            res.choices[0].message.content(),
+            Some("Mock reply to: Hello!")
+        );
+
+        let res = sdk
+            .embedding(EmbeddingRequest::new(vec![
+                "a cat".to_string(),
+                "a dog".to_string(),
+            ]))
+            .await?;
+        assert_eq!(res.data.len(), 2);
+        assert_eq!(res.data[1].embedding(), mock_embedding("a dog"));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].endpoint(), Some(Endpoint::ChatCompletions));
+        assert_eq!(requests[0].headers["authorization"], "Bearer sk-mock");
+        assert_eq!(
+            requests[0].json().unwrap()["messages"][0]["content"],
+            "Hello!"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mock_server_should_inject_errors() -> Result<()> {
+        let server = MockServer::start().await;
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::error(StatusCode::TOO_MANY_REQUESTS, "Rate limit reached")
+                .with_header("retry-after", "0"),
+        );
+        server.enqueue(Endpoint::ChatCompletions, MockResponse::chat("Finally."));
+
+        let err = server
+            .sdk()
+            .chat_completion(chat_request())
+            .await
+            .unwrap_err();
+        assert!(err.is_rate_limited());
+        assert_eq!(err.api_error().unwrap().message, "Rate limit reached");
+        let res = server.sdk().chat_completion(chat_request()).await?;
+        assert_eq!(res.choices[0].message.content(), Some("Finally."));
+
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::error(StatusCode::SERVICE_UNAVAILABLE, "Overloaded"),
+        );
+        let retry = RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        let sdk = LLmSdk::new(server.url(), "").with_retry_policy(retry);
+        assert!(sdk.chat_completion(chat_request()).await.is_ok());
+        assert_eq!(server.requests().len(), 4);
+        Ok(())
+    }
+}
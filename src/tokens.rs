@@ -0,0 +1,522 @@
+use crate::{ChatCompletionMessage, ChatCompletionRequest, LlmError, Result, Tool};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::{fmt, sync::Arc};
+
+/// Tokens added to every message by the chat format.
+const TOKENS_PER_MESSAGE: usize = 3;
+/// Tokens added when a message has a name.
+const TOKENS_PER_NAME: usize = 1;
+/// Every reply is primed with `<|start|>assistant<|message|>`.
+const REPLY_PRIMING: usize = 3;
+/// Tokens of an image sent with `detail: low`.
+const LOW_DETAIL_IMAGE: usize = 85;
+/// The size of an image is not known, so `auto` and `high` images are counted as 1024x1024: 4 tiles of 170 tokens plus 85.
+const HIGH_DETAIL_IMAGE: usize = 765;
+
+/// Counts the tokens of a text.
+pub trait Tokenizer: fmt::Debug + Send + Sync {
+    fn count(&self, text: &str) -> usize;
+}
+
+/// Estimate of 4 characters per token, used when the BPE tables are not bundled.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CharEstimate;
+
+impl Tokenizer for CharEstimate {
+    fn count(&self, text: &str) -> usize {
+        text.chars().count().div_ceil(4)
+    }
+}
+
+/// The exact tokenizer of the OpenAI models, with the `tiktoken` feature.
+#[cfg(feature = "tiktoken")]
+#[derive(Clone, Copy)]
+pub struct Bpe {
+    name: &'static str,
+    bpe: &'static tiktoken_rs::CoreBPE,
+}
+
+#[cfg(feature = "tiktoken")]
+impl Bpe {
+    /// The encoding of gpt-3.5 and gpt-4.
+    pub fn cl100k_base() -> Self {
+        static BPE: std::sync::OnceLock<tiktoken_rs::CoreBPE> = std::sync::OnceLock::new();
+        Self {
+            name: "cl100k_base",
+            bpe: BPE.get_or_init(|| tiktoken_rs::cl100k_base().unwrap()),
+        }
+    }
+
+    /// The encoding of gpt-4o and the o-series models.
+    pub fn o200k_base() -> Self {
+        static BPE: std::sync::OnceLock<tiktoken_rs::CoreBPE> = std::sync::OnceLock::new();
+        Self {
+            name: "o200k_base",
+            bpe: BPE.get_or_init(|| tiktoken_rs::o200k_base().unwrap()),
+        }
+    }
+
+    /// The encoding used by `model`, cl100k_base for the models it doesn't know.
+    pub fn for_model(model: &str) -> Self {
+        let o200k = ["gpt-4o", "gpt-4.1", "gpt-5", "o1", "o3", "o4"];
+        match o200k.iter().any(|prefix| model.starts_with(prefix)) {
+            true => Self::o200k_base(),
+            false => Self::cl100k_base(),
+        }
+    }
+}
+
+#[cfg(feature = "tiktoken")]
+impl Tokenizer for Bpe {
+    fn count(&self, text: &str) -> usize {
+        self.bpe.encode_ordinary(text).len()
+    }
+}
+
+#[cfg(feature = "tiktoken")]
+impl fmt::Debug for Bpe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Bpe").field(&self.name).finish()
+    }
+}
+
+/// Summarizes messages dropped from a conversation, see `TokenCounter::summarize_middle`.
+#[async_trait]
+pub trait Summarizer: Send + Sync {
+    /// A single message standing in for `messages`.
+    async fn summarize(&self, messages: &[ChatCompletionMessage]) -> Result<ChatCompletionMessage>;
+}
+
+/// How `TokenCounter::truncate` makes a request fit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Truncation {
+    /// Drop the oldest messages after the system messages until the request fits.
+    DropOldest,
+    /// Keep the system messages and the last `n` messages, then drop the oldest of them if the request still doesn't fit.
+    /// The last message is always kept, so `KeepLast(0)` is the same as `KeepLast(1)`.
+    KeepLast(usize),
+}
+
+/// Counts the tokens of requests, following the per-message overhead rules of the OpenAI chat format.
+#[derive(Debug, Clone)]
+pub struct TokenCounter {
+    tokenizer: Arc<dyn Tokenizer>,
+}
+
+impl TokenCounter {
+    pub fn new(tokenizer: impl Tokenizer + 'static) -> Self {
+        Self {
+            tokenizer: Arc::new(tokenizer),
+        }
+    }
+
+    /// The tokenizer of `model`, an estimate without the `tiktoken` feature.
+    pub fn for_model(model: impl AsRef<str>) -> Self {
+        #[cfg(feature = "tiktoken")]
+        return Self::new(Bpe::for_model(model.as_ref()));
+        #[cfg(not(feature = "tiktoken"))]
+        {
+            let _ = model;
+            Self::new(CharEstimate)
+        }
+    }
+
+    pub fn count_text(&self, text: &str) -> usize {
+        self.tokenizer.count(text)
+    }
+
+    /// The tokens of a message, including the overhead of the chat format.
+    pub fn count_message(&self, message: &ChatCompletionMessage) -> usize {
+        let Ok(Value::Object(fields)) = serde_json::to_value(message) else {
+            return TOKENS_PER_MESSAGE;
+        };
+        let mut tokens = TOKENS_PER_MESSAGE;
+        for (key, value) in &fields {
+            tokens += match (key.as_str(), value) {
+                ("content", Value::Array(parts)) => parts.iter().map(|p| self.count_part(p)).sum(),
+                ("tool_calls", Value::Array(calls)) => calls
+                    .iter()
+                    .map(|call| {
+                        let function = &call["function"];
+                        self.count_str(&function["name"]) + self.count_str(&function["arguments"])
+                    })
+                    .sum(),
+                ("name", value) => self.count_str(value) + TOKENS_PER_NAME,
+                (_, value) => self.count_str(value),
+            };
+        }
+        tokens
+    }
+
+    /// The prompt tokens of `messages`, including the priming of the reply.
+    pub fn count_messages(&self, messages: &[ChatCompletionMessage]) -> usize {
+        messages
+            .iter()
+            .map(|m| self.count_message(m))
+            .sum::<usize>()
+            + REPLY_PRIMING
+    }
+
+    /// The tokens of the tool definitions, which the model sees as part of the system prompt.
+    pub fn count_tools(&self, tools: &[Tool]) -> usize {
+        const FUNCTION_INIT: usize = 7;
+        const PROPERTIES_INIT: usize = 3;
+        const PROPERTY_KEY: usize = 3;
+        // an enum takes 3 tokens less than the other properties
+        const ENUM_INIT: usize = 3;
+        const ENUM_ITEM: usize = 3;
+        const FUNCTIONS_END: usize = 12;
+
+        if tools.is_empty() {
+            return 0;
+        }
+        let mut tokens = FUNCTIONS_END;
+        for tool in tools {
+            let Ok(tool) = serde_json::to_value(tool) else {
+                continue;
+            };
+            let function = &tool["function"];
+            let description = function["description"].as_str().unwrap_or_default();
+            tokens += FUNCTION_INIT
+                + self.count_text(&format!(
+                    "{}:{}",
+                    function["name"].as_str().unwrap_or_default(),
+                    description.trim_end_matches('.')
+                ));
+
+            let Some(properties) = function["parameters"]["properties"].as_object() else {
+                continue;
+            };
+            if properties.is_empty() {
+                continue;
+            }
+            tokens += PROPERTIES_INIT;
+            for (name, property) in properties {
+                tokens += PROPERTY_KEY;
+                if let Some(items) = property["enum"].as_array() {
+                    tokens = tokens.saturating_sub(ENUM_INIT);
+                    for item in items {
+                        let item = item.as_str().map_or_else(|| item.to_string(), Into::into);
+                        tokens += ENUM_ITEM + self.count_text(&item);
+                    }
+                }
+                let ty = match &property["type"] {
+                    Value::String(ty) => ty.clone(),
+                    Value::Null => String::new(),
+                    ty => ty.to_string(),
+                };
+                let description = property["description"].as_str().unwrap_or_default();
+                tokens += self.count_text(&format!(
+                    "{}:{}:{}",
+                    name,
+                    ty,
+                    description.trim_end_matches('.')
+                ));
+            }
+        }
+        tokens
+    }
+
+    /// The prompt tokens of a request: its messages and its tools.
+    pub fn count_request(&self, req: &ChatCompletionRequest) -> usize {
+        self.count_messages(req.messages()) + self.count_tools(req.tools())
+    }
+
+    /// Drop messages from `req` until its prompt fits in `budget` tokens. The system messages and the last
+    /// message are always kept, it's an error if they don't fit.
+    pub fn truncate(
+        &self,
+        req: &mut ChatCompletionRequest,
+        budget: usize,
+        strategy: Truncation,
+    ) -> Result<()> {
+        let start = system_prefix(req.messages());
+        if let Truncation::KeepLast(n) = strategy {
+            let messages = req.messages_mut();
+            let end = messages.len().saturating_sub(n.max(1)).max(start);
+            messages.drain(start..end);
+        }
+        self.drop_oldest(req, budget, start)
+    }
+
+    /// Replace the messages between the system messages and the last `keep_last` messages by a summary,
+    /// then drop the oldest messages after the summary if the request still doesn't fit in `budget` tokens.
+    /// Nothing is summarized if the request already fits.
+    pub async fn summarize_middle(
+        &self,
+        req: &mut ChatCompletionRequest,
+        budget: usize,
+        keep_last: usize,
+        summarizer: &dyn Summarizer,
+    ) -> Result<()> {
+        if self.count_request(req) <= budget {
+            return Ok(());
+        }
+        let start = system_prefix(req.messages());
+        let mut end = req.messages().len().saturating_sub(keep_last).max(start);
+        // don't separate tool results from the call they answer
+        while end > start
+            && end < req.messages().len()
+            && matches!(req.messages()[end], ChatCompletionMessage::Tool(_))
+        {
+            end -= 1;
+        }
+        if end > start {
+            let summary = summarizer.summarize(&req.messages()[start..end]).await?;
+            req.messages_mut().splice(start..end, [summary]);
+            self.drop_oldest(req, budget, start + 1)
+        } else {
+            self.drop_oldest(req, budget, start)
+        }
+    }
+
+    fn drop_oldest(
+        &self,
+        req: &mut ChatCompletionRequest,
+        budget: usize,
+        start: usize,
+    ) -> Result<()> {
+        let mut tokens = self.count_request(req);
+        while tokens > budget {
+            let messages = req.messages_mut();
+            if messages.len() <= start + 1 {
+                return Err(LlmError::Validation(format!(
+                    "the request needs {} tokens, more than the budget of {}",
+                    tokens, budget
+                )));
+            }
+            messages.remove(start);
+            tokens = self.count_request(req);
+        }
+        // a tool result can't come without the call it answers
+        let messages = req.messages_mut();
+        while messages.len() > start + 1
+            && matches!(messages[start], ChatCompletionMessage::Tool(_))
+        {
+            messages.remove(start);
+        }
+        Ok(())
+    }
+
+    fn count_str(&self, value: &Value) -> usize {
+        value.as_str().map_or(0, |s| self.count_text(s))
+    }
+
+    fn count_part(&self, part: &Value) -> usize {
+        match part["type"].as_str() {
+            Some("image_url") if part["image_url"]["detail"] == "low" => LOW_DETAIL_IMAGE,
+            Some("image_url") => HIGH_DETAIL_IMAGE,
+            _ => self.count_str(&part["text"]),
+        }
+    }
+}
+
+/// The number of system messages at the start of `messages`.
+fn system_prefix(messages: &[ChatCompletionMessage]) -> usize {
+    messages
+        .iter()
+        .take_while(|m| matches!(m, ChatCompletionMessage::System(_)))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChatCompletionRequestBuilder, ContentPart, ImageDetail};
+    use schemars::JsonSchema;
+
+    /// One token per word, to make the counts easy to follow.
+    #[derive(Debug)]
+    struct Words;
+
+    impl Tokenizer for Words {
+        fn count(&self, text: &str) -> usize {
+            text.split_whitespace().count()
+        }
+    }
+
+    struct Summary;
+
+    #[async_trait]
+    impl Summarizer for Summary {
+        async fn summarize(
+            &self,
+            messages: &[ChatCompletionMessage],
+        ) -> Result<ChatCompletionMessage> {
+            Ok(ChatCompletionMessage::new_system(
+                format!("{} messages", messages.len()),
+                "",
+            ))
+        }
+    }
+
+    fn request() -> ChatCompletionRequest {
+        ChatCompletionRequestBuilder::default()
+            .messages(vec![
+                ChatCompletionMessage::new_system("be brief", ""),
+                ChatCompletionMessage::new_user("one two three", ""),
+                ChatCompletionMessage::new_user("four five", ""),
+                ChatCompletionMessage::new_user("six", ""),
+                ChatCompletionMessage::new_user("seven eight", ""),
+            ])
+            .build()
+            .unwrap()
+    }
+
+    fn contents(req: &ChatCompletionRequest) -> Vec<String> {
+        req.messages()
+            .iter()
+            .map(|m| serde_json::to_value(m).unwrap()["content"].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn count_messages_should_add_overhead() {
+        let counter = TokenCounter::new(Words);
+        // 3 + role + words per message, 3 for the reply
+        assert_eq!(
+            counter.count_request(&request()),
+            4 * 5 + 2 + 3 + 2 + 1 + 2 + 3
+        );
+
+        let message = ChatCompletionMessage::new_user(
+            vec![
+                ContentPart::text("what is it"),
+                ContentPart::image_url("https://example.com/a.png", Some(ImageDetail::Low)),
+                ContentPart::image_url("https://example.com/b.png", None),
+            ],
+            "tyr",
+        );
+        assert_eq!(
+            counter.count_message(&message),
+            3 + 1 + 3 + 85 + 765 + 1 + 1
+        );
+    }
+
+    #[derive(JsonSchema)]
+    #[allow(dead_code)]
+    struct WeatherArgs {
+        /// The city.
+        city: String,
+        /// Temperature unit.
+        unit: String,
+    }
+
+    #[test]
+    fn count_tools_should_work() {
+        let counter = TokenCounter::new(Words);
+        assert_eq!(counter.count_tools(&[]), 0);
+
+        let tool = Tool::new_function::<WeatherArgs>("get_weather", "Get the weather.");
+        // "get_weather:Get the weather", "city:string:The city", "unit:string:Temperature unit"
+        assert_eq!(
+            counter.count_tools(&[tool]),
+            12 + 7 + 3 + 3 + (3 + 2) + (3 + 2)
+        );
+    }
+
+    #[test]
+    fn truncate_should_drop_oldest_messages() -> Result<()> {
+        let counter = TokenCounter::new(Words);
+
+        let mut req = request();
+        counter.truncate(&mut req, 20, Truncation::DropOldest)?;
+        assert_eq!(
+            contents(&req),
+            ["\"be brief\"", "\"six\"", "\"seven eight\""]
+        );
+
+        let mut req = request();
+        counter.truncate(&mut req, 100, Truncation::KeepLast(1))?;
+        assert_eq!(contents(&req), ["\"be brief\"", "\"seven eight\""]);
+
+        let mut req = request();
+        counter.truncate(&mut req, 100, Truncation::KeepLast(0))?;
+        assert_eq!(contents(&req), ["\"be brief\"", "\"seven eight\""]);
+
+        let mut req = request();
+        let err = counter
+            .truncate(&mut req, 5, Truncation::DropOldest)
+            .unwrap_err();
+        assert!(matches!(err, LlmError::Validation(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn summarize_middle_should_replace_old_messages() -> Result<()> {
+        let counter = TokenCounter::new(Words);
+
+        let mut req = request();
+        counter.summarize_middle(&mut req, 100, 1, &Summary).await?;
+        assert_eq!(req.messages().len(), 5);
+
+        counter.summarize_middle(&mut req, 26, 2, &Summary).await?;
+        assert_eq!(
+            contents(&req),
+            [
+                "\"be brief\"",
+                "\"2 messages\"",
+                "\"six\"",
+                "\"seven eight\""
+            ]
+        );
+
+        let mut req = request();
+        counter.summarize_middle(&mut req, 26, 0, &Summary).await?;
+        assert_eq!(contents(&req), ["\"be brief\"", "\"4 messages\""]);
+        Ok(())
+    }
+
+    #[test]
+    fn fit_to_context_should_use_model_budget() -> Result<()> {
+        let sdk = crate::LLmSdkBuilder::default()
+            .model(
+                crate::ModelInfoBuilder::default()
+                    .id("tiny")
+                    .context_window(40)
+                    .build()
+                    .unwrap(),
+            )
+            .build()?;
+        let long = "word ".repeat(30);
+        let mut req = ChatCompletionRequestBuilder::default()
+            .model("tiny")
+            .messages(vec![
+                ChatCompletionMessage::new_user(long, ""),
+                ChatCompletionMessage::new_user("hi", ""),
+            ])
+            .build()
+            .unwrap();
+        sdk.fit_to_context(&mut req, Truncation::DropOldest)?;
+        assert_eq!(req.messages().len(), 1);
+
+        let mut req = ChatCompletionRequestBuilder::default()
+            .model("unknown")
+            .messages(vec![])
+            .build()
+            .unwrap();
+        let err = sdk
+            .fit_to_context(&mut req, Truncation::DropOldest)
+            .unwrap_err();
+        assert!(matches!(err, LlmError::Config(_)));
+        Ok(())
+    }
+
+    #[cfg(feature = "tiktoken")]
+    #[test]
+    fn bpe_should_count_tokens() {
+        let counter = TokenCounter::for_model("gpt-3.5-turbo");
+        assert_eq!(counter.count_text("hello world"), 2);
+
+        let messages = [
+            ChatCompletionMessage::new_system("You are a helpful assistant.", ""),
+            ChatCompletionMessage::new_user("Hello!", ""),
+        ];
+        assert_eq!(counter.count_messages(&messages), 19);
+        assert_eq!(
+            TokenCounter::for_model("gpt-4o").count_messages(&messages),
+            19
+        );
+    }
+}
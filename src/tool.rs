@@ -0,0 +1,309 @@
+use crate::{ChatCompletionMessage, ToSchema, Tool, ToolCalls};
+use futures::{future::BoxFuture, FutureExt};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashMap, fmt, future::Future};
+
+type Handler = Box<dyn Fn(&str) -> BoxFuture<'static, Result<String, String>> + Send + Sync>;
+
+/// Tools the model may call, along with the async handlers that run them. Used by `LLmSdk::run_with_tools`.
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: Vec<Tool>,
+    handlers: HashMap<String, Handler>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `handler` as the tool `name`. The tool parameters are the json schema of `T`, and the model's
+    /// arguments are deserialized into `T` before calling the handler. The result is sent back to the model as json.
+    pub fn register<T, R, E, F, Fut>(
+        &mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        handler: F,
+    ) -> &mut Self
+    where
+        T: ToSchema + DeserializeOwned + Send + 'static,
+        R: Serialize,
+        E: fmt::Display,
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R, E>> + Send + 'static,
+    {
+        let name = name.into();
+        self.tools.retain(|t| t.name() != name);
+        self.tools
+            .push(Tool::new_function::<T>(name.clone(), description));
+
+        let handler: Handler = Box::new(move |arguments: &str| {
+            let fut = serde_json::from_str::<T>(arguments).map(&handler);
+            async move {
+                let fut = fut.map_err(|e| format!("invalid arguments: {}", e))?;
+                let ret = fut.await.map_err(|e| e.to_string())?;
+                serde_json::to_string(&ret).map_err(|e| e.to_string())
+            }
+            .boxed()
+        });
+        self.handlers.insert(name, handler);
+        self
+    }
+
+    /// Register a tool function, usually generated by `#[tool]`.
+    pub fn add<T: ToolFunction>(&mut self, tool: T) -> &mut Self {
+        self.register(T::NAME, T::DESCRIPTION, move |args| tool.call(args))
+    }
+
+    /// The tool definitions to send to the model.
+    pub fn tools(&self) -> Vec<Tool> {
+        self.tools.clone()
+    }
+
+    /// Run a tool call of the model and return the tool message answering it.
+    /// Failures are reported to the model in the message, so it can correct itself.
+    pub async fn call(&self, call: &ToolCalls) -> ChatCompletionMessage {
+        let ret = match self.handlers.get(call.name()) {
+            Some(handler) => handler(call.arguments()).await,
+            None => Err(format!("unknown tool: {}", call.name())),
+        };
+        let content = ret.unwrap_or_else(|e| {
+            tracing::warn!("tool {} failed: {}", call.name(), e);
+            serde_json::json!({ "error": e }).to_string()
+        });
+        ChatCompletionMessage::new_tool(content, call.id())
+    }
+}
+
+/// A function the model can call, usually generated from an async fn by `#[tool]`.
+pub trait ToolFunction: Send + Sync + 'static {
+    /// The parameters of the function, described to the model by their json schema.
+    type Args: ToSchema + DeserializeOwned + Send + 'static;
+    /// What the function returns, sent back to the model as json.
+    type Output: Serialize + Send + 'static;
+    /// The name of the tool.
+    const NAME: &'static str;
+    /// What the function does, used by the model to choose when and how to call it.
+    const DESCRIPTION: &'static str;
+
+    fn call(&self, args: Self::Args) -> BoxFuture<'static, Result<Self::Output, String>>;
+
+    /// The tool definition to send to the model.
+    fn tool() -> Tool {
+        Tool::new_function::<Self::Args>(Self::NAME, Self::DESCRIPTION)
+    }
+
+    /// Deserialize the arguments generated by the model and call the function.
+    fn dispatch(&self, arguments: &str) -> BoxFuture<'static, Result<Self::Output, String>> {
+        match serde_json::from_str(arguments) {
+            Ok(args) => self.call(args),
+            Err(e) => futures::future::ready(Err(format!("invalid arguments: {}", e))).boxed(),
+        }
+    }
+}
+
+impl fmt::Debug for ToolRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ToolRegistry")
+            .field("tools", &self.tools)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{MockResponse, MockServer},
+        ChatCompletionMessage, ChatCompletionRequestBuilder, Endpoint, FinishReason,
+    };
+    use anyhow::Result;
+    use schemars::JsonSchema;
+    use serde::Deserialize;
+
+    #[derive(Debug, JsonSchema, Deserialize)]
+    struct GetWeatherArgs {
+        /// Get the weather for the city
+        city: String,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct GetWeatherResponse {
+        city: String,
+        temperature: f32,
+    }
+
+    async fn get_weather_forecast(args: GetWeatherArgs) -> Result<GetWeatherResponse, String> {
+        if args.city.is_empty() {
+            return Err("city is required".into());
+        }
+        Ok(GetWeatherResponse {
+            city: args.city,
+            temperature: 22.1,
+        })
+    }
+
+    fn registry() -> ToolRegistry {
+        let mut registry = ToolRegistry::new();
+        registry.register(
+            "get_weather_forecast",
+            "Get the weather forecast for a city.",
+            get_weather_forecast,
+        );
+        registry
+    }
+
+    fn tool_call(name: &str, arguments: &str) -> ToolCalls {
+        serde_json::from_value(serde_json::json!({
+            "id": "call_1",
+            "type": "function",
+            "function": { "name": name, "arguments": arguments }
+        }))
+        .unwrap()
+    }
+
+    fn tool_content(message: ChatCompletionMessage) -> serde_json::Value {
+        let json = serde_json::to_value(message).unwrap();
+        assert_eq!(json["role"], "tool");
+        assert_eq!(json["tool_call_id"], "call_1");
+        serde_json::from_str(json["content"].as_str().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn tool_registry_call_should_work() {
+        let registry = registry();
+        assert_eq!(registry.tools().len(), 1);
+
+        let ret = registry
+            .call(&tool_call("get_weather_forecast", r#"{"city":"ShangHai"}"#))
+            .await;
+        assert_eq!(
+            tool_content(ret),
+            serde_json::json!({ "city": "ShangHai", "temperature": 22.1 })
+        );
+    }
+
+    #[tokio::test]
+    async fn tool_registry_call_should_report_errors() {
+        let registry = registry();
+
+        let ret = registry
+            .call(&tool_call("get_weather_forecast", r#"{"city":""}"#))
+            .await;
+        assert_eq!(
+            tool_content(ret),
+            serde_json::json!({ "error": "city is required" })
+        );
+
+        let ret = registry
+            .call(&tool_call("get_weather_forecast", r#"{"town":"ShangHai"}"#))
+            .await;
+        assert!(tool_content(ret)["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid arguments"));
+
+        let ret = registry.call(&tool_call("explain_mood", "{}")).await;
+        assert_eq!(
+            tool_content(ret),
+            serde_json::json!({ "error": "unknown tool: explain_mood" })
+        );
+    }
+
+    /// Explain the meaning of the given mood.
+    #[crate::tool]
+    async fn explain_mood(
+        /// The mood to explain.
+        name: String,
+        /// Answer in uppercase.
+        shout: Option<bool>,
+    ) -> Result<String, String> {
+        match name.as_str() {
+            "" => Err("mood is required".into()),
+            _ if shout.unwrap_or_default() => Ok(format!("{} IS A MOOD", name.to_uppercase())),
+            _ => Ok(format!("{} is a mood", name)),
+        }
+    }
+
+    #[test]
+    fn tool_macro_should_generate_tool() {
+        let json = serde_json::to_value(ExplainMoodTool::tool()).unwrap();
+        assert_eq!(json["type"], "function");
+        assert_eq!(json["function"]["name"], "explain_mood");
+        assert_eq!(
+            json["function"]["description"],
+            "Explain the meaning of the given mood."
+        );
+        assert_eq!(json["function"]["parameters"], ExplainMoodArgs::to_schema());
+        let properties = &json["function"]["parameters"]["properties"];
+        assert_eq!(properties["name"]["description"], "The mood to explain.");
+        assert_eq!(properties["shout"]["description"], "Answer in uppercase.");
+        assert_eq!(
+            json["function"]["parameters"]["required"],
+            serde_json::json!(["name"])
+        );
+    }
+
+    #[tokio::test]
+    async fn tool_macro_dispatch_should_work() {
+        let tool = ExplainMoodTool;
+        assert_eq!(
+            tool.dispatch(r#"{"name":"happy","shout":true}"#).await,
+            Ok("HAPPY IS A MOOD".to_string())
+        );
+        assert_eq!(
+            tool.dispatch(r#"{"name":""}"#).await,
+            Err("mood is required".to_string())
+        );
+        assert!(tool
+            .dispatch("{}")
+            .await
+            .unwrap_err()
+            .starts_with("invalid arguments"));
+
+        let mut registry = ToolRegistry::new();
+        registry.add(ExplainMoodTool);
+        let ret = registry
+            .call(&tool_call("explain_mood", r#"{"name":"sad"}"#))
+            .await;
+        assert_eq!(tool_content(ret), serde_json::json!("sad is a mood"));
+    }
+
+    #[tokio::test]
+    async fn run_with_tools_should_work() -> Result<()> {
+        let server = MockServer::start().await;
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::tool_call(
+                "get_weather_forecast",
+                serde_json::json!({ "city": "ShangHai" }),
+            ),
+        );
+        server.enqueue(
+            Endpoint::ChatCompletions,
+            MockResponse::chat("It is 22.1 degrees in ShangHai."),
+        );
+        let sdk = server.sdk();
+        let mut req = ChatCompletionRequestBuilder::default()
+            .messages(vec![
+                ChatCompletionMessage::new_system("I can choose the right function for you.", ""),
+                ChatCompletionMessage::new_user("What is the weather like in ShangHai?", ""),
+            ])
+            .build()?;
+        let res = sdk.run_with_tools(&mut req, &registry(), 3).await?;
+
+        assert_eq!(res.choices[0].finish_reason, FinishReason::Stop);
+        assert!(res.choices[0].message.content().unwrap().contains("22"));
+        assert_eq!(req.messages().len(), 4);
+        assert_eq!(res.usage.total_tokens, 30);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        let tool = &requests[1].json().unwrap()["messages"][3];
+        assert_eq!(tool["role"], "tool");
+        assert_eq!(tool["tool_call_id"], "call_get_weather_forecast");
+        assert!(tool["content"].as_str().unwrap().contains("22.1"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,821 @@
+use crate::{
+    decode_f32s, encode_f32s, EmbeddingBatching, EmbeddingInput, EmbeddingModel,
+    EmbeddingRequestBuilder, LLmSdk, LlmError, Result,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{collections::HashMap, path::Path};
+
+/// How `VectorStore` scores a vector against the query. Higher scores are more similar.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Distance {
+    /// The cosine of the angle between the vectors.
+    #[default]
+    Cosine,
+    /// The dot product, the same as cosine for normalized vectors like OpenAI embeddings.
+    Dot,
+    /// The euclidean distance, negated.
+    Euclidean,
+}
+
+/// A text stored in a `VectorStore`, with metadata to filter searches.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Document {
+    pub id: String,
+    pub text: String,
+    #[serde(default)]
+    pub metadata: Map<String, Value>,
+}
+
+/// A filter on the metadata of documents.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// The metadata `key` equals the value.
+    Eq(String, Value),
+    /// The metadata `key` is one of the values.
+    In(String, Vec<Value>),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+/// A document found by `VectorStore::search`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SearchResult<'a> {
+    pub document: &'a Document,
+    pub score: f32,
+}
+
+/// Settings of the HNSW index. Larger values give better recall for slower inserts and searches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HnswConfig {
+    /// The number of neighbors of a vector, twice as many on the bottom layer.
+    pub m: usize,
+    /// The number of candidates considered when inserting.
+    pub ef_construction: usize,
+    /// The number of candidates considered when searching, at least `k`.
+    pub ef_search: usize,
+}
+
+/// An in-memory store of documents and their embeddings, searched by similarity.
+///
+/// Searches scan every vector, unless an HNSW index is enabled with `with_hnsw` for large collections.
+/// The index is approximate: it may miss some of the closest vectors, but is much faster.
+#[derive(Debug, Clone, Default)]
+pub struct VectorStore {
+    distance: Distance,
+    embedding_model: EmbeddingModel,
+    /// Removed entries stay in place as tombstones, so the index can still go through them,
+    /// until they are compacted away.
+    entries: Vec<Entry>,
+    ids: HashMap<String, usize>,
+    hnsw: Option<hnsw::Hnsw>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    document: Document,
+    embedding: Vec<f32>,
+    norm: f32,
+    removed: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoreFile {
+    distance: Distance,
+    embedding_model: EmbeddingModel,
+    hnsw: Option<HnswConfig>,
+    documents: Vec<StoredDocument>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredDocument {
+    #[serde(flatten)]
+    document: Document,
+    /// Little-endian f32s in base64, much smaller than a json array.
+    embedding: String,
+}
+
+impl Document {
+    pub fn new(id: impl Into<String>, text: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            text: text.into(),
+            metadata: Map::new(),
+        }
+    }
+
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+}
+
+impl Filter {
+    pub fn eq(key: impl Into<String>, value: impl Into<Value>) -> Self {
+        Filter::Eq(key.into(), value.into())
+    }
+
+    pub fn is_in(
+        key: impl Into<String>,
+        values: impl IntoIterator<Item = impl Into<Value>>,
+    ) -> Self {
+        Filter::In(key.into(), values.into_iter().map(Into::into).collect())
+    }
+
+    pub fn matches(&self, metadata: &Map<String, Value>) -> bool {
+        match self {
+            Filter::Eq(key, value) => metadata.get(key) == Some(value),
+            Filter::In(key, values) => metadata.get(key).is_some_and(|v| values.contains(v)),
+            Filter::And(filters) => filters.iter().all(|f| f.matches(metadata)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(metadata)),
+            Filter::Not(filter) => !filter.matches(metadata),
+        }
+    }
+}
+
+impl Default for HnswConfig {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 200,
+            ef_search: 64,
+        }
+    }
+}
+
+impl VectorStore {
+    pub fn new(distance: Distance) -> Self {
+        Self {
+            distance,
+            ..Default::default()
+        }
+    }
+
+    /// The model embedding the documents and queries in `add` and `search_text`.
+    pub fn with_embedding_model(mut self, model: impl Into<EmbeddingModel>) -> Self {
+        self.embedding_model = model.into();
+        self
+    }
+
+    /// Index the vectors with HNSW, for approximate but fast searches.
+    pub fn with_hnsw(mut self, config: HnswConfig) -> Self {
+        self.hnsw = Some(self.build_index(config));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Document> {
+        self.ids.get(id).map(|&slot| &self.entries[slot].document)
+    }
+
+    pub fn documents(&self) -> impl Iterator<Item = &Document> {
+        self.entries
+            .iter()
+            .filter(|e| !e.removed)
+            .map(|e| &e.document)
+    }
+
+    /// Store `document` with its `embedding`, replacing the document with the same id.
+    /// All the embeddings must have the same size.
+    pub fn insert(&mut self, document: Document, embedding: Vec<f32>) -> Result<()> {
+        let size = self.entries.first().map(|e| e.embedding.len());
+        if size.is_some_and(|size| size != embedding.len()) {
+            return Err(LlmError::Validation(format!(
+                "embedding of {} has {} dimensions, the store has {}",
+                document.id,
+                embedding.len(),
+                size.unwrap_or_default()
+            )));
+        }
+        self.remove(&document.id);
+
+        let slot = self.entries.len();
+        self.ids.insert(document.id.clone(), slot);
+        self.entries.push(Entry {
+            norm: norm(&embedding),
+            document,
+            embedding,
+            removed: false,
+        });
+        if let Some(index) = &mut self.hnsw {
+            index.insert(slot, &self.entries, self.distance);
+        }
+        Ok(())
+    }
+
+    /// Embed `documents` with `sdk` and store them. Large collections are embedded in batches.
+    pub async fn add(&mut self, sdk: &LLmSdk, documents: Vec<Document>) -> Result<()> {
+        if documents.is_empty() {
+            return Ok(());
+        }
+        let texts = documents.iter().map(|d| d.text.clone()).collect();
+        let embeddings = self.embed(sdk, texts).await?;
+        for (document, embedding) in documents.into_iter().zip(embeddings) {
+            self.insert(document, embedding)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the document `id`. Returns whether it was there.
+    /// The store is compacted once more than half of its entries are removed ones.
+    pub fn remove(&mut self, id: &str) -> bool {
+        let Some(slot) = self.ids.remove(id) else {
+            return false;
+        };
+        self.entries[slot].removed = true;
+        if self.entries.len() - self.ids.len() > self.entries.len() / 2 {
+            self.compact();
+        }
+        true
+    }
+
+    /// Drop the removed entries and rebuild the index without them.
+    pub fn compact(&mut self) {
+        self.entries.retain(|e| !e.removed);
+        self.ids = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(slot, e)| (e.document.id.clone(), slot))
+            .collect();
+        if let Some(index) = &self.hnsw {
+            self.hnsw = Some(self.build_index(index.config()));
+        }
+    }
+
+    /// The `k` documents most similar to `query` matching `filter`, most similar first.
+    /// A query whose size differs from the stored embeddings matches nothing.
+    pub fn search(
+        &self,
+        query: &[f32],
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> Vec<SearchResult<'_>> {
+        let size = self
+            .entries
+            .first()
+            .map_or(query.len(), |e| e.embedding.len());
+        if query.len() != size {
+            tracing::warn!(
+                "query has {} dimensions, the store has {}, nothing matches",
+                query.len(),
+                size
+            );
+            return vec![];
+        }
+        let matches = |e: &Entry| {
+            !e.removed
+                && match filter {
+                    Some(filter) => filter.matches(&e.document.metadata),
+                    None => true,
+                }
+        };
+        let query_norm = norm(query);
+        let mut scored: Vec<_> = match &self.hnsw {
+            Some(index) => {
+                let found: Vec<_> = index
+                    .search(query, k, &self.entries, self.distance)
+                    .into_iter()
+                    .filter(|&(_, slot)| matches(&self.entries[slot]))
+                    .collect();
+                // The filter may have dropped too many candidates of the index.
+                if found.len() < k && filter.is_some() {
+                    self.scan(query, query_norm, matches)
+                } else {
+                    found
+                }
+            }
+            None => self.scan(query, query_norm, matches),
+        };
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored
+            .into_iter()
+            .take(k)
+            .map(|(score, slot)| SearchResult {
+                document: &self.entries[slot].document,
+                score,
+            })
+            .collect()
+    }
+
+    /// Embed `query` with `sdk` and `search` for it.
+    pub async fn search_text(
+        &self,
+        sdk: &LLmSdk,
+        query: &str,
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<SearchResult<'_>>> {
+        if self.is_empty() || k == 0 {
+            return Ok(vec![]);
+        }
+        let query = self.embed(sdk, vec![query.to_string()]).await?.remove(0);
+        Ok(self.search(&query, k, filter))
+    }
+
+    /// Save the documents and their embeddings to `path`, as json.
+    pub async fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = StoreFile {
+            distance: self.distance,
+            embedding_model: self.embedding_model.clone(),
+            hnsw: self.hnsw.as_ref().map(|index| index.config()),
+            documents: self
+                .entries
+                .iter()
+                .filter(|e| !e.removed)
+                .map(|e| StoredDocument {
+                    document: e.document.clone(),
+                    embedding: encode_f32s(&e.embedding),
+                })
+                .collect(),
+        };
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(&file)?)
+            .await
+            .map_err(io_error)?;
+        tokio::fs::rename(&tmp, path).await.map_err(io_error)
+    }
+
+    /// Load a store saved with `save`, rebuilding its index.
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let data = tokio::fs::read(path).await.map_err(io_error)?;
+        let file: StoreFile = serde_json::from_slice(&data)?;
+        let mut store = Self::new(file.distance).with_embedding_model(file.embedding_model);
+        if let Some(config) = file.hnsw {
+            store = store.with_hnsw(config);
+        }
+        for stored in file.documents {
+            let embedding = decode_f32s(&stored.embedding).ok_or_else(|| {
+                LlmError::Storage(format!("invalid embedding of {}", stored.document.id))
+            })?;
+            store.insert(stored.document, embedding)?;
+        }
+        Ok(store)
+    }
+
+    fn build_index(&self, config: HnswConfig) -> hnsw::Hnsw {
+        let mut index = hnsw::Hnsw::new(config);
+        for slot in 0..self.entries.len() {
+            index.insert(slot, &self.entries, self.distance);
+        }
+        index
+    }
+
+    fn scan(
+        &self,
+        query: &[f32],
+        query_norm: f32,
+        matches: impl Fn(&Entry) -> bool,
+    ) -> Vec<(f32, usize)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| matches(e))
+            .map(|(slot, e)| {
+                (
+                    score(self.distance, query, query_norm, &e.embedding, e.norm),
+                    slot,
+                )
+            })
+            .collect()
+    }
+
+    async fn embed(&self, sdk: &LLmSdk, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let req = EmbeddingRequestBuilder::default()
+            .input(EmbeddingInput::from(texts))
+            .model(self.embedding_model.clone())
+            .build()
+            .map_err(|e| LlmError::Config(e.to_string()))?;
+        let data = sdk
+            .embed_many(req, &EmbeddingBatching::default())
+            .await?
+            .data;
+        Ok(data.into_iter().map(|d| d.into_embedding()).collect())
+    }
+}
+
+fn io_error(e: std::io::Error) -> LlmError {
+    LlmError::Storage(e.to_string())
+}
+
+fn score(distance: Distance, a: &[f32], a_norm: f32, b: &[f32], b_norm: f32) -> f32 {
+    match distance {
+        Distance::Cosine if a_norm == 0.0 || b_norm == 0.0 => 0.0,
+        Distance::Cosine => dot(a, b) / (a_norm * b_norm),
+        Distance::Dot => dot(a, b),
+        Distance::Euclidean => {
+            let squared: f32 = a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum();
+            -squared.sqrt()
+        }
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn norm(v: &[f32]) -> f32 {
+    dot(v, v).sqrt()
+}
+
+/// Hierarchical navigable small world graphs, from "Efficient and robust approximate nearest neighbor
+/// search using Hierarchical Navigable Small World graphs", Malkov and Yashunin.
+mod hnsw {
+    use super::{score, Distance, Entry, HnswConfig};
+    use std::{
+        cmp::{Ordering, Reverse},
+        collections::{BinaryHeap, HashSet},
+    };
+
+    #[derive(Debug, Clone)]
+    pub(super) struct Hnsw {
+        config: HnswConfig,
+        /// The neighbors of every slot, on every layer the slot is in.
+        neighbors: Vec<Vec<Vec<usize>>>,
+        entry: Option<usize>,
+        /// State of the xorshift generator drawing the layers.
+        seed: u64,
+    }
+
+    /// A vector and its norm.
+    type Query<'a> = (&'a [f32], f32);
+
+    /// A slot with its score, ordered by score.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Scored(f32, usize);
+
+    impl Eq for Scored {}
+
+    impl PartialOrd for Scored {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Scored {
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+        }
+    }
+
+    impl Hnsw {
+        pub(super) fn new(config: HnswConfig) -> Self {
+            Self {
+                config,
+                neighbors: vec![],
+                entry: None,
+                seed: 0x2545f4914f6cdd1d,
+            }
+        }
+
+        pub(super) fn config(&self) -> HnswConfig {
+            self.config
+        }
+
+        pub(super) fn insert(&mut self, slot: usize, entries: &[Entry], distance: Distance) {
+            let level = self.random_level();
+            self.neighbors.resize(slot + 1, vec![]);
+            self.neighbors[slot] = vec![vec![]; level + 1];
+            let Some(mut entry) = self.entry else {
+                self.entry = Some(slot);
+                return;
+            };
+
+            let query = (&entries[slot].embedding[..], entries[slot].norm);
+            let top = self.neighbors[entry].len() - 1;
+            for layer in (level + 1..=top).rev() {
+                entry = self.greedy(query, entry, layer, entries, distance);
+            }
+            for layer in (0..=level.min(top)).rev() {
+                let candidates = self.search_layer(
+                    query,
+                    entry,
+                    self.config.ef_construction,
+                    layer,
+                    entries,
+                    distance,
+                );
+                entry = candidates[0].1;
+                let max = self.max_neighbors(layer);
+                let selected: Vec<_> = candidates
+                    .iter()
+                    .filter(|c| c.1 != slot)
+                    .take(max)
+                    .map(|c| c.1)
+                    .collect();
+                for &neighbor in &selected {
+                    self.neighbors[neighbor][layer].push(slot);
+                    if self.neighbors[neighbor][layer].len() > max {
+                        self.prune(neighbor, layer, max, entries, distance);
+                    }
+                }
+                self.neighbors[slot][layer] = selected;
+            }
+            if level > top {
+                self.entry = Some(slot);
+            }
+        }
+
+        /// The scores and slots of about the `k` closest vectors to `query`, removed ones aside.
+        pub(super) fn search(
+            &self,
+            query: &[f32],
+            k: usize,
+            entries: &[Entry],
+            distance: Distance,
+        ) -> Vec<(f32, usize)> {
+            let Some(mut entry) = self.entry else {
+                return vec![];
+            };
+            let query = (query, super::norm(query));
+            for layer in (1..self.neighbors[entry].len()).rev() {
+                entry = self.greedy(query, entry, layer, entries, distance);
+            }
+            let ef = self.config.ef_search.max(k);
+            self.search_layer(query, entry, ef, 0, entries, distance)
+                .into_iter()
+                .filter(|c| !entries[c.1].removed)
+                .map(|c| (c.0, c.1))
+                .collect()
+        }
+
+        fn max_neighbors(&self, layer: usize) -> usize {
+            match layer {
+                0 => self.config.m * 2,
+                _ => self.config.m,
+            }
+        }
+
+        fn random_level(&mut self) -> usize {
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 7;
+            self.seed ^= self.seed << 17;
+            let uniform = (self.seed >> 11) as f64 / (1u64 << 53) as f64;
+            let ml = 1.0 / (self.config.m.max(2) as f64).ln();
+            (-(1.0 - uniform).ln() * ml) as usize
+        }
+
+        fn score(&self, query: Query, slot: usize, entries: &[Entry], distance: Distance) -> f32 {
+            let other = &entries[slot];
+            score(distance, query.0, query.1, &other.embedding, other.norm)
+        }
+
+        /// Walk to the closest neighbor of `entry` until none is closer.
+        fn greedy(
+            &self,
+            query: Query,
+            mut entry: usize,
+            layer: usize,
+            entries: &[Entry],
+            distance: Distance,
+        ) -> usize {
+            let mut best = self.score(query, entry, entries, distance);
+            loop {
+                let closer = self.neighbors[entry][layer]
+                    .iter()
+                    .map(|&n| Scored(self.score(query, n, entries, distance), n))
+                    .max()
+                    .filter(|c| c.0 > best);
+                match closer {
+                    Some(Scored(score, slot)) => {
+                        best = score;
+                        entry = slot;
+                    }
+                    None => return entry,
+                }
+            }
+        }
+
+        /// The `ef` closest vectors to `query` found from `entry` on `layer`, closest first.
+        fn search_layer(
+            &self,
+            query: Query,
+            entry: usize,
+            ef: usize,
+            layer: usize,
+            entries: &[Entry],
+            distance: Distance,
+        ) -> Vec<Scored> {
+            let first = Scored(self.score(query, entry, entries, distance), entry);
+            let mut visited = HashSet::from([entry]);
+            let mut candidates = BinaryHeap::from([first]);
+            let mut found = BinaryHeap::from([Reverse(first)]);
+            while let Some(candidate) = candidates.pop() {
+                let worst = found.peek().map(|w| w.0 .0).unwrap_or(f32::MIN);
+                if candidate.0 < worst && found.len() >= ef {
+                    break;
+                }
+                for &neighbor in &self.neighbors[candidate.1][layer] {
+                    if !visited.insert(neighbor) {
+                        continue;
+                    }
+                    let scored = Scored(self.score(query, neighbor, entries, distance), neighbor);
+                    let worst = found.peek().map(|w| w.0 .0).unwrap_or(f32::MIN);
+                    if found.len() < ef || scored.0 > worst {
+                        candidates.push(scored);
+                        found.push(Reverse(scored));
+                        if found.len() > ef {
+                            found.pop();
+                        }
+                    }
+                }
+            }
+            let mut found: Vec<_> = found.into_iter().map(|r| r.0).collect();
+            found.sort_by(|a, b| b.cmp(a));
+            found
+        }
+
+        /// Keep the `max` closest neighbors of `slot` on `layer`.
+        fn prune(
+            &mut self,
+            slot: usize,
+            layer: usize,
+            max: usize,
+            entries: &[Entry],
+            distance: Distance,
+        ) {
+            let node = (&entries[slot].embedding[..], entries[slot].norm);
+            let mut scored: Vec<_> = self.neighbors[slot][layer]
+                .iter()
+                .map(|&n| Scored(self.score(node, n, entries, distance), n))
+                .collect();
+            scored.sort_by(|a, b| b.cmp(a));
+            self.neighbors[slot][layer] = scored.into_iter().take(max).map(|s| s.1).collect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{temp_path, MockServer};
+
+    fn sample(distance: Distance) -> Result<VectorStore> {
+        let mut store = VectorStore::new(distance);
+        store.insert(
+            Document::new("x", "east").with_metadata("axis", "x"),
+            vec![1.0, 0.0],
+        )?;
+        store.insert(
+            Document::new("y", "north").with_metadata("axis", "y"),
+            vec![0.0, 1.0],
+        )?;
+        store.insert(
+            Document::new("xy", "north east").with_metadata("axis", "xy"),
+            vec![2.0, 2.0],
+        )?;
+        Ok(store)
+    }
+
+    fn ids(results: &[SearchResult]) -> Vec<String> {
+        results.iter().map(|r| r.document.id.clone()).collect()
+    }
+
+    /// A deterministic pseudo-random vector.
+    fn vector(seed: usize, size: usize) -> Vec<f32> {
+        let mut state = (seed as u64).wrapping_mul(0x9e3779b97f4a7c15) | 1;
+        (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state % 2000) as f32 / 1000.0 - 1.0
+            })
+            .collect()
+    }
+
+    #[test]
+    fn search_should_rank_by_distance() -> Result<()> {
+        let query = [1.0, 0.2];
+        let store = sample(Distance::Cosine)?;
+        assert_eq!(ids(&store.search(&query, 3, None)), ["x", "xy", "y"]);
+        assert!((store.search(&query, 1, None)[0].score - 0.98058).abs() < 1e-4);
+
+        let store = sample(Distance::Dot)?;
+        assert_eq!(ids(&store.search(&query, 3, None)), ["xy", "x", "y"]);
+
+        let store = sample(Distance::Euclidean)?;
+        assert_eq!(ids(&store.search(&query, 2, None)), ["x", "y"]);
+
+        assert!(store.search(&[1.0], 3, None).is_empty());
+        assert!(store.search(&[1.0, 0.2, 0.0], 3, None).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn search_should_filter_and_skip_removed() -> Result<()> {
+        let mut store = sample(Distance::Cosine)?;
+        let filter = Filter::is_in("axis", ["y", "xy"]);
+        assert_eq!(
+            ids(&store.search(&[1.0, 0.0], 3, Some(&filter))),
+            ["xy", "y"]
+        );
+        let filter = Filter::Not(Box::new(Filter::eq("axis", "xy")));
+        assert_eq!(ids(&store.search(&[1.0, 1.0], 1, Some(&filter))), ["x"]);
+
+        assert!(store.remove("xy"));
+        assert!(!store.remove("xy"));
+        assert_eq!(store.len(), 2);
+        assert_eq!(ids(&store.search(&[1.0, 1.0], 3, None)), ["x", "y"]);
+
+        store.insert(Document::new("x", "west"), vec![-1.0, 0.0])?;
+        assert_eq!(store.get("x").unwrap().text, "west");
+        assert_eq!(ids(&store.search(&[1.0, 0.0], 1, None)), ["y"]);
+        assert!(matches!(
+            store.insert(Document::new("z", "up"), vec![0.0, 0.0, 1.0]),
+            Err(LlmError::Validation(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn hnsw_should_find_nearest_neighbors() -> Result<()> {
+        let mut exact = VectorStore::new(Distance::Cosine);
+        let mut approx = VectorStore::new(Distance::Cosine).with_hnsw(HnswConfig::default());
+        for i in 0..1000 {
+            let document = Document::new(i.to_string(), "").with_metadata("even", i % 2 == 0);
+            exact.insert(document.clone(), vector(i, 16))?;
+            approx.insert(document, vector(i, 16))?;
+        }
+        approx.remove("7");
+
+        let mut hits = 0;
+        for q in 0..20 {
+            let query = vector(10_000 + q, 16);
+            let expected = ids(&exact.search(&query, 10, None));
+            let found = ids(&approx.search(&query, 10, None));
+            assert!(!found.contains(&"7".to_string()));
+            hits += found.iter().filter(|id| expected.contains(id)).count();
+        }
+        assert!(hits >= 180, "recall {}/200", hits);
+
+        let filter = Filter::eq("even", true);
+        let found = approx.search(&vector(3, 16), 10, Some(&filter));
+        assert_eq!(found.len(), 10);
+        assert!(found.iter().all(|r| r.document.metadata["even"] == true));
+        Ok(())
+    }
+
+    #[test]
+    fn removed_entries_should_be_compacted() -> Result<()> {
+        let mut store = VectorStore::new(Distance::Cosine).with_hnsw(HnswConfig::default());
+        for i in 0..100 {
+            store.insert(Document::new((i % 10).to_string(), ""), vector(i, 8))?;
+        }
+        assert_eq!(store.len(), 10);
+        assert!(store.entries.len() <= 20, "{} entries", store.entries.len());
+        assert_eq!(store.search(&vector(95, 8), 1, None)[0].document.id, "5");
+
+        store.remove("5");
+        store.compact();
+        assert_eq!(store.entries.len(), 9);
+        assert_eq!(store.get("9").unwrap().id, "9");
+        assert_eq!(store.search(&vector(99, 8), 1, None)[0].document.id, "9");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn vector_store_should_embed_and_persist() -> Result<()> {
+        let server = MockServer::start().await;
+        let sdk = server.sdk();
+        let mut store = VectorStore::new(Distance::Cosine).with_hnsw(HnswConfig::default());
+        store
+            .add(
+                &sdk,
+                vec![
+                    Document::new("tea", "Green tea is healthy."),
+                    Document::new("city", "ShangHai is a great city.")
+                        .with_metadata("kind", "place"),
+                ],
+            )
+            .await?;
+        let found = store
+            .search_text(&sdk, "Which city is great?", 1, None)
+            .await?;
+        assert_eq!(ids(&found), ["city"]);
+
+        let path = temp_path("store.json");
+        store.save(&path).await?;
+        let loaded = VectorStore::load(&path).await?;
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get("city"), store.get("city"));
+        let query = crate::testing::mock_embedding("Which city is great?");
+        assert_eq!(
+            ids(&loaded.search(&query, 2, None)),
+            ids(&store.search(&query, 2, None))
+        );
+        Ok(())
+    }
+}